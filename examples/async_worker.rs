@@ -0,0 +1,28 @@
+use orizuru::AsyncConsumer;
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, Debug)]
+struct Job {
+    id: u64,
+}
+
+#[tokio::main]
+async fn main() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let con = client.get_async_connection().await.unwrap();
+    let worker =
+        AsyncConsumer::new("consumer-1".into(), "orizuru-example".into(), con);
+
+    println!("Starting consumer with queue `default`");
+
+    while let Some(task) = worker.next::<Job>().await {
+        if task.is_err() {
+            continue;
+        }
+
+        let mut task = task.unwrap();
+
+        println!("Task: {:?}", task.payload());
+        task.ack().await.unwrap();
+    }
+}
@@ -1,5 +1,6 @@
-use orizuru::{Consumer, Producer};
+use orizuru::{Consumer, Producer, GC, PROCESSING_QUEUE_KEY, UNACKED_QUEUE_KEY};
 use serde::{Deserialize, Serialize};
+use std::env;
 use std::process::Command;
 use std::str::FromStr;
 use std::time::{Duration, Instant};
@@ -44,7 +45,69 @@ fn process_rss(pid: u32) -> u64 {
     FromStr::from_str(&s[1..end]).unwrap()
 }
 
+fn fill_unacked(con: &mut redis::Connection, queue: &str, n: usize) {
+    let payload = rmp_serde::to_vec(&Job { id: 0 }).unwrap();
+    let mut pipe = redis::pipe();
+    for _ in 0..n {
+        pipe.cmd("LPUSH")
+            .arg(queue)
+            .arg(payload.as_slice())
+            .ignore();
+    }
+    let _: () = pipe.query(con).unwrap();
+}
+
+// Compare the per-message RPOPLPUSH loop the garbage collector used to run
+// with the batched Lua script.
+fn bench_gc(n: usize) {
+    let consumer = "bench-gc";
+    let unacked = UNACKED_QUEUE_KEY.replace("{consumer}", consumer);
+    let processing = PROCESSING_QUEUE_KEY.replace("{consumer}", consumer);
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let mut con = client.get_connection().unwrap();
+    let _: () = redis::cmd("DEL")
+        .arg(&unacked)
+        .arg(&processing)
+        .query(&mut con)
+        .unwrap();
+
+    fill_unacked(&mut con, &unacked, n);
+    let now = Instant::now();
+    let len: usize = redis::cmd("LLEN").arg(&unacked).query(&mut con).unwrap();
+    for _ in 0..len {
+        let _: redis::Value = redis::cmd("RPOPLPUSH")
+            .arg(&unacked)
+            .arg(&processing)
+            .query(&mut con)
+            .unwrap();
+    }
+    let naive = now.elapsed();
+    println!("RPOPLPUSH loop: {} messages in {:?}", n, naive);
+
+    fill_unacked(&mut con, &unacked, n);
+    let gc = GC::new(client.get_connection().unwrap());
+    let now = Instant::now();
+    let collected = gc.collect_one(consumer).unwrap();
+    let scripted = now.elapsed();
+    println!("Lua script: {} messages in {:?}", collected, scripted);
+    println!(
+        "Speedup: {:.1}x",
+        naive.as_secs_f64() / scripted.as_secs_f64()
+    );
+
+    let _: () = redis::cmd("DEL")
+        .arg(&unacked)
+        .arg(&processing)
+        .query(&mut con)
+        .unwrap();
+}
+
 fn main() {
+    if env::args().nth(1).as_deref() == Some("gc") {
+        bench_gc(100_000);
+        return;
+    }
+
     let total = 10 * 10_000;
     load(total);
 
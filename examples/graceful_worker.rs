@@ -0,0 +1,41 @@
+use orizuru::{Consumer, Shutdown};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+#[derive(Deserialize, Serialize, Debug)]
+struct Job {
+    id: u64,
+}
+
+fn main() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let con = client.get_connection().unwrap();
+    let mut worker =
+        Consumer::new("consumer-1".into(), "orizuru-example".into(), con);
+    worker.set_block_timeout(Duration::from_secs(1));
+    worker.set_fetch_connection(client.get_connection().unwrap());
+    worker.register().unwrap();
+
+    let worker = Arc::new(worker);
+    let consumer = worker.clone();
+    let handle = thread::spawn(move || {
+        while let Some(task) = consumer.next::<Job>() {
+            if task.is_err() {
+                continue;
+            }
+
+            let mut task = task.unwrap();
+
+            println!("Task: {:?}", task.payload());
+            thread::sleep(Duration::from_millis(500));
+            task.ack().unwrap();
+        }
+    });
+
+    let shutdown = Shutdown::new(Duration::from_secs(5));
+    let n = shutdown.run(&worker).unwrap();
+    println!("Shut down, requeued {} unfinished tasks", n);
+    handle.join().unwrap();
+}
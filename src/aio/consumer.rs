@@ -0,0 +1,491 @@
+use crate::aio::message::AsyncMessageGuard;
+use crate::codec::{Codec, MsgPackCodec};
+use crate::consumer::{
+    block_timeout_secs, StopHandle, CONSUMERS_KEY, DEAD_LETTER_QUEUE_KEY,
+    HEARTBEATS_KEY, HEARTBEAT_KEY, PROCESSING_QUEUE_KEY, SOURCES_KEY,
+    STOP_CHECK_SECS, UNACKED_QUEUE_KEY,
+};
+use crate::dead_letter::DeadLetter;
+use crate::encryption::Keyring;
+use crate::envelope;
+use crate::error::Error;
+use crate::heartbeat;
+use crate::message;
+use crate::signing::{SignaturePolicy, Signer};
+use futures::lock::Mutex;
+use futures::stream::{self, Stream};
+use redis::{aio, AsyncCommands, Value};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub struct AsyncConsumer<C: Codec = MsgPackCodec> {
+    name: String,
+    source_queue_name: String,
+    processing_queue_name: String,
+    unacked_queue_name: String,
+    sources_key: String,
+    heartbeat_key: String,
+    block_timeout: Duration,
+    codec: C,
+    keyring: Option<Keyring>,
+    signer: Option<Signer>,
+    signature_policy: SignaturePolicy,
+    stopped: Arc<AtomicBool>,
+    client: Mutex<aio::Connection>,
+    fetch_client: Option<Mutex<aio::Connection>>,
+    // Payloads of the messages whose guard was dropped without being acked.
+    // `Drop` cannot run async code, so they are rejected on the next call to
+    // `AsyncConsumer::next()`.
+    dropped: std::sync::Mutex<Vec<Vec<u8>>>,
+}
+
+impl AsyncConsumer {
+    pub fn new(
+        name: String,
+        source_queue_name: String,
+        client: aio::Connection,
+    ) -> AsyncConsumer {
+        AsyncConsumer::build(name, source_queue_name, client, None)
+    }
+
+    /// Create a consumer that fetches messages with a dedicated connection.
+    ///
+    /// Fetching blocks the connection until a message is available. With a
+    /// separate connection, messages can be acknowledged or rejected while
+    /// the next one is being fetched, which `stream()` requires so that it
+    /// can be consumed with e.g. `buffer_unordered`.
+    pub fn with_fetch_connection(
+        name: String,
+        source_queue_name: String,
+        client: aio::Connection,
+        fetch_client: aio::Connection,
+    ) -> AsyncConsumer {
+        AsyncConsumer::build(name, source_queue_name, client, Some(fetch_client))
+    }
+
+    fn build(
+        name: String,
+        source_queue_name: String,
+        client: aio::Connection,
+        fetch_client: Option<aio::Connection>,
+    ) -> AsyncConsumer {
+        let processing_queue_name =
+            PROCESSING_QUEUE_KEY.replace("{consumer}", name.as_str());
+        let unacked_queue_name =
+            UNACKED_QUEUE_KEY.replace("{consumer}", name.as_str());
+        let sources_key = SOURCES_KEY.replace("{consumer}", name.as_str());
+        let heartbeat_key = HEARTBEAT_KEY.replace("{consumer}", name.as_str());
+
+        AsyncConsumer {
+            name,
+            source_queue_name,
+            processing_queue_name,
+            unacked_queue_name,
+            sources_key,
+            heartbeat_key,
+            block_timeout: Duration::from_secs(0),
+            codec: MsgPackCodec,
+            keyring: None,
+            signer: None,
+            signature_policy: SignaturePolicy::DeadLetter,
+            stopped: Arc::new(AtomicBool::new(false)),
+            client: Mutex::new(client),
+            fetch_client: fetch_client.map(Mutex::new),
+            dropped: std::sync::Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl<C: Codec> AsyncConsumer<C> {
+    /// Register this consumer to enable automatic discovery by the garbage
+    /// collector.
+    ///
+    /// The source queue is recorded as well, so that the garbage collector can
+    /// requeue the messages of the consumer if it dies.
+    pub async fn register(&self) -> Result<Value, Error> {
+        let mut client = self.client.lock().await;
+        let (added,): (Value,) = redis::pipe()
+            .atomic()
+            .cmd("SADD")
+            .arg(CONSUMERS_KEY)
+            .arg(self.name.as_str())
+            .cmd("DEL")
+            .arg(self.sources_key.as_str())
+            .ignore()
+            .cmd("RPUSH")
+            .arg(self.sources_key.as_str())
+            .arg(self.source_queue_name.as_str())
+            .ignore()
+            .query_async(&mut *client)
+            .await?;
+        Ok(added)
+    }
+
+    pub async fn deregister(&self) -> Result<Value, Error> {
+        let mut client = self.client.lock().await;
+        let (removed,): (Value,) = redis::pipe()
+            .atomic()
+            .cmd("SREM")
+            .arg(CONSUMERS_KEY)
+            .arg(self.name.as_str())
+            .cmd("DEL")
+            .arg(self.sources_key.as_str())
+            .ignore()
+            .cmd("HDEL")
+            .arg(HEARTBEATS_KEY)
+            .arg(self.name.as_str())
+            .ignore()
+            .query_async(&mut *client)
+            .await?;
+        Ok(removed)
+    }
+
+    /// Stop processing the queue.
+    /// The next `AsyncConsumer::next()` call will return `None`. A call that
+    /// is already waiting for a job returns `None` within the block timeout,
+    /// or a second without one.
+    ///
+    /// Guards dropped without being acknowledged after the last call to
+    /// `next()` are still in the *processing* queue: `reject_dropped()` must be
+    /// awaited on shutdown to move them to the *unack* queue.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    /// Check if queue processing is stopped.
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Get a handle that can stop this consumer from another thread or task.
+    pub fn stop_handle(&self) -> StopHandle {
+        StopHandle::new(self.stopped.clone())
+    }
+
+    /// Set how long `AsyncConsumer::next()` waits for a job before checking
+    /// again whether the consumer has been stopped. A zero timeout (the
+    /// default) waits indefinitely, checking every second. See
+    /// `Consumer::set_block_timeout()`.
+    pub fn set_block_timeout(&mut self, timeout: Duration) {
+        self.block_timeout = timeout;
+    }
+
+    /// Get the block timeout.
+    pub fn block_timeout(&self) -> Duration {
+        self.block_timeout
+    }
+
+    /// Turn this consumer into one decoding bare payloads with the given
+    /// codec. See `Consumer::with_codec()`.
+    pub fn with_codec<D: Codec>(self, codec: D) -> AsyncConsumer<D> {
+        AsyncConsumer {
+            name: self.name,
+            source_queue_name: self.source_queue_name,
+            processing_queue_name: self.processing_queue_name,
+            unacked_queue_name: self.unacked_queue_name,
+            sources_key: self.sources_key,
+            heartbeat_key: self.heartbeat_key,
+            block_timeout: self.block_timeout,
+            codec,
+            keyring: self.keyring,
+            signer: self.signer,
+            signature_policy: self.signature_policy,
+            stopped: self.stopped,
+            client: self.client,
+            fetch_client: self.fetch_client,
+            dropped: self.dropped,
+        }
+    }
+
+    /// Get the codec.
+    pub fn codec(&self) -> &C {
+        &self.codec
+    }
+
+    /// Set the keyring encrypted messages are decrypted with. As with
+    /// `Consumer`, messages that cannot be decrypted are moved to the
+    /// dead-letter queue and reported with `Error::Decrypt`.
+    pub fn set_keyring(&mut self, keyring: Keyring) {
+        self.keyring = Some(keyring);
+    }
+
+    /// Get the keyring.
+    pub fn keyring(&self) -> Option<&Keyring> {
+        self.keyring.as_ref()
+    }
+
+    /// Set the signer messages are verified with, before being decoded.
+    ///
+    /// Messages that fail verification are handled according to the signature
+    /// policy and reported with `Error::Signature`. Since the async consumer
+    /// keeps no rejections hash, rejected messages are only moved to the
+    /// *unacked* queue.
+    pub fn set_signer(&mut self, signer: Signer) {
+        self.signer = Some(signer);
+    }
+
+    /// Get the signer.
+    pub fn signer(&self) -> Option<&Signer> {
+        self.signer.as_ref()
+    }
+
+    /// Set what to do with the messages that fail verification. Defaults to
+    /// `SignaturePolicy::DeadLetter`.
+    pub fn set_signature_policy(&mut self, policy: SignaturePolicy) {
+        self.signature_policy = policy;
+    }
+
+    /// Get the signature policy.
+    pub fn signature_policy(&self) -> SignaturePolicy {
+        self.signature_policy
+    }
+
+    /// Get the name of the consumer.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get the heartbeat key.
+    pub fn heartbeat_key(&self) -> &str {
+        &self.heartbeat_key
+    }
+
+    /// Get the source queue name.
+    pub fn source_queue(&self) -> &str {
+        &self.source_queue_name
+    }
+
+    /// Get the processing queue name.
+    pub fn processing_queue(&self) -> &str {
+        &self.processing_queue_name
+    }
+
+    /// Get the unacked queue name.
+    pub fn unacked_queue(&self) -> &str {
+        &self.unacked_queue_name
+    }
+
+    /// Get the connection used by this consumer.
+    pub fn client(&self) -> &Mutex<aio::Connection> {
+        &self.client
+    }
+
+    /// Get the number of remaining jobs in the queue.
+    pub async fn size(&self) -> u64 {
+        let mut client = self.client.lock().await;
+        client
+            .llen(self.source_queue_name.as_str())
+            .await
+            .unwrap_or(0)
+    }
+
+    pub async fn heartbeat(&self, ttl: Duration) -> u128 {
+        let (ts, pipe) =
+            heartbeat::pipe(HEARTBEATS_KEY, &self.heartbeat_key, &self.name, ttl);
+        let mut client = self.client.lock().await;
+        let _: redis::RedisResult<()> = pipe.query_async(&mut *client).await;
+        ts
+    }
+
+    /// Grab the next job from the queue.
+    ///
+    /// The returned future resolves once a new job is available. It resolves
+    /// to None if the consumer has been stopped (with the stop() method).
+    /// Otherwise it resolves to a Result value that may wrap the message.
+    ///
+    /// Unless the consumer was created with `with_fetch_connection()`, the
+    /// connection is held until a job is available, so acknowledgements from
+    /// other tasks sharing this consumer wait for it.
+    ///
+    /// The guards dropped without being acknowledged are rejected first, even
+    /// if the consumer has been stopped.
+    pub async fn next<'a, T: message::MessageDecodable + 'a>(
+        &'a self,
+    ) -> Option<Result<AsyncMessageGuard<'a, T, C>, Error>> {
+        if let Err(e) = self.reject_dropped().await {
+            return Some(Err(e));
+        }
+
+        let source = &self.source_queue_name[..];
+        let processing = &self.processing_queue_name[..];
+        let timeout = match block_timeout_secs(self.block_timeout) {
+            0 => STOP_CHECK_SECS,
+            timeout => timeout,
+        };
+        let fetch_client = match self.fetch_client {
+            Some(ref c) => c,
+            None => &self.client,
+        };
+
+        let v = loop {
+            if self.is_stopped() {
+                // Guards may have been dropped while waiting.
+                return match self.reject_dropped().await {
+                    Ok(()) => None,
+                    Err(e) => Some(Err(e)),
+                };
+            }
+
+            let mut client = fetch_client.lock().await;
+            match client.brpoplpush(source, processing, timeout).await {
+                Ok(Value::Nil) => continue,
+                Ok(v) => break v,
+                Err(e) => return Some(Err(e.into())),
+            }
+        };
+
+        let payload = match v {
+            Value::Data(payload) => payload,
+            v => return Some(Err(Error::UnexpectedReply(v))),
+        };
+        if let Some(Err(e)) = self.signer().map(|s| s.verify(&payload)) {
+            if let Err(e) = self.discard_unverified(payload, &e.to_string()).await
+            {
+                return Some(Err(e));
+            }
+            return Some(Err(e));
+        }
+        match envelope::decode_payload(&payload, &self.codec, self.keyring()) {
+            Err(e) if e.is_decrypt() => {
+                let reason = e.to_string();
+                if let Err(e) = self.dead_letter_payload(payload, &reason).await {
+                    return Some(Err(e));
+                }
+                Some(Err(e))
+            }
+            Err(e) => Some(Err(e)),
+            Ok((message, envelope)) => {
+                Some(Ok(AsyncMessageGuard::new(message, payload, envelope, self)))
+            }
+        }
+    }
+
+    /// Get a stream of jobs from the queue.
+    ///
+    /// The stream yields the same items as repeated calls to `next()` and
+    /// terminates once the consumer has been stopped, within the block timeout
+    /// (or a second without one) if a fetch is pending. Guards dropped after
+    /// the end of the stream are only rejected by `reject_dropped()`, which
+    /// must be awaited on shutdown.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the consumer was not created with `with_fetch_connection()`:
+    /// the items of the stream are processed while the next one is fetched,
+    /// and their acknowledgements would wait for the fetch otherwise.
+    pub fn stream<'a, T: message::MessageDecodable + 'a>(
+        &'a self,
+    ) -> impl Stream<Item = Result<AsyncMessageGuard<'a, T, C>, Error>> + 'a {
+        assert!(
+            self.fetch_client.is_some(),
+            "streams require a fetch connection"
+        );
+        stream::unfold(self, |consumer| async move {
+            consumer.next::<T>().await.map(|item| (item, consumer))
+        })
+    }
+
+    /// Move a payload from the *processing* queue to the dead-letter queue,
+    /// without decoding it.
+    async fn dead_letter_payload(
+        &self,
+        payload: Vec<u8>,
+        reason: &str,
+    ) -> Result<(), Error> {
+        let envelope = envelope::Envelope::parse(&payload)
+            .ok()
+            .and_then(|(envelope, _)| envelope);
+        let entry = DeadLetter::new(
+            payload.clone(),
+            envelope.as_ref(),
+            reason,
+            &self.name,
+        );
+        let mut client = self.client.lock().await;
+        Ok(redis::pipe()
+            .atomic()
+            .cmd("LPUSH")
+            .arg(DEAD_LETTER_QUEUE_KEY.replace("{queue}", &self.source_queue_name))
+            .arg(entry.encode()?)
+            .ignore()
+            .cmd("LREM")
+            .arg(self.processing_queue_name.as_str())
+            .arg(1)
+            .arg(payload)
+            .ignore()
+            .query_async(&mut *client)
+            .await?)
+    }
+
+    /// Handle a payload that failed verification according to the signature
+    /// policy.
+    async fn discard_unverified(
+        &self,
+        payload: Vec<u8>,
+        reason: &str,
+    ) -> Result<(), Error> {
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        match self.signature_policy {
+            SignaturePolicy::Reject => {
+                pipe.cmd("LPUSH")
+                    .arg(self.unacked_queue_name.as_str())
+                    .arg(payload.as_slice())
+                    .ignore();
+            }
+            SignaturePolicy::DeadLetter => {
+                return self.dead_letter_payload(payload, reason).await
+            }
+            SignaturePolicy::Drop => (),
+        }
+        let mut client = self.client.lock().await;
+        Ok(pipe
+            .cmd("LREM")
+            .arg(self.processing_queue_name.as_str())
+            .arg(1)
+            .arg(payload)
+            .ignore()
+            .query_async(&mut *client)
+            .await?)
+    }
+
+    pub(crate) fn push_dropped(&self, payload: Vec<u8>) {
+        if let Ok(mut dropped) = self.dropped.lock() {
+            dropped.push(payload);
+        }
+    }
+
+    /// Move the messages whose guard was dropped while still unacked from the
+    /// *processing* queue to the *unack* queue.
+    pub async fn reject_dropped(&self) -> Result<(), Error> {
+        let payloads = match self.dropped.lock() {
+            Ok(mut dropped) => std::mem::take(&mut *dropped),
+            Err(_) => return Ok(()),
+        };
+
+        for (i, payload) in payloads.iter().enumerate() {
+            let mut client = self.client.lock().await;
+            let res: redis::RedisResult<()> = redis::pipe()
+                .atomic()
+                .cmd("LPUSH")
+                .arg(self.unacked_queue_name.as_str())
+                .arg(payload.as_slice())
+                .ignore()
+                .cmd("LREM")
+                .arg(self.processing_queue_name.as_str())
+                .arg(1)
+                .arg(payload.as_slice())
+                .ignore()
+                .query_async(&mut *client)
+                .await;
+            if let Err(e) = res {
+                if let Ok(mut dropped) = self.dropped.lock() {
+                    dropped.extend(payloads.into_iter().skip(i));
+                }
+                return Err(e.into());
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,125 @@
+use crate::aio::consumer::AsyncConsumer;
+use crate::codec::{Codec, MsgPackCodec};
+use crate::envelope::Envelope;
+use crate::error::Error;
+use crate::message::MessageState;
+use redis::{AsyncCommands, Value};
+use std::ops::{Deref, Drop};
+
+pub struct AsyncMessageGuard<'a, T: 'a, C: Codec = MsgPackCodec> {
+    message: T,
+    payload: Vec<u8>,
+    envelope: Option<Envelope>,
+    consumer: &'a AsyncConsumer<C>,
+    state: MessageState,
+}
+
+impl<'a, T, C: Codec> AsyncMessageGuard<'a, T, C> {
+    pub fn new(
+        message: T,
+        payload: Vec<u8>,
+        envelope: Option<Envelope>,
+        consumer: &'a AsyncConsumer<C>,
+    ) -> AsyncMessageGuard<'a, T, C> {
+        AsyncMessageGuard {
+            message,
+            payload,
+            envelope,
+            consumer,
+            state: MessageState::Unacked,
+        }
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// Get the envelope of the message, if it was pushed with one.
+    pub fn envelope(&self) -> Option<&Envelope> {
+        self.envelope.as_ref()
+    }
+
+    /// Get the unique id of the message, if it was pushed with an envelope.
+    pub fn id(&self) -> Option<&str> {
+        self.envelope.as_ref().map(Envelope::id)
+    }
+
+    /// Get the time the message was first pushed, in milliseconds since the
+    /// Unix epoch, if it was pushed with an envelope.
+    pub fn created_at(&self) -> Option<u64> {
+        self.envelope.as_ref().map(Envelope::created_at)
+    }
+
+    /// Get the number of failed delivery attempts of the message.
+    pub fn attempts(&self) -> u32 {
+        self.envelope.as_ref().map_or(0, Envelope::attempts)
+    }
+
+    /// Get the value of a header of the message.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.envelope.as_ref().and_then(|e| e.header(name))
+    }
+
+    pub fn message(&self) -> &T {
+        &self.message
+    }
+
+    /// Acknowledge the message and remove it from the *processing* queue.
+    pub async fn ack(&mut self) -> Result<Value, Error> {
+        self.state = MessageState::Acked;
+        let mut client = self.consumer.client().lock().await;
+        Ok(client
+            .lrem(self.consumer.processing_queue(), 1, self.payload.as_slice())
+            .await?)
+    }
+
+    /// Reject the message and push it from the *processing* queue to the
+    /// *unack* queue.
+    pub async fn reject(&mut self) -> Result<Value, Error> {
+        self.state = MessageState::Rejected;
+        let unacked_queue_name = self.consumer.unacked_queue().to_string();
+        self.push(unacked_queue_name).await
+    }
+
+    /// Remove the message from the processing queue and push it to the
+    /// specified queue. It can be used to implement retries.
+    pub async fn push(&mut self, push_queue_name: String) -> Result<Value, Error> {
+        self.state = MessageState::Pushed;
+        let mut client = self.consumer.client().lock().await;
+        Ok(redis::pipe()
+            .atomic()
+            .cmd("LPUSH")
+            .arg(push_queue_name.as_str())
+            .arg(self.payload.as_slice())
+            .ignore()
+            .cmd("LREM")
+            .arg(self.consumer.processing_queue())
+            .arg(1)
+            .arg(self.payload.as_slice())
+            .ignore()
+            .query_async(&mut *client)
+            .await?)
+    }
+
+    pub fn consumer(&self) -> &AsyncConsumer<C> {
+        self.consumer
+    }
+}
+
+impl<'a, T, C: Codec> Deref for AsyncMessageGuard<'a, T, C> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.message
+    }
+}
+
+impl<'a, T, C: Codec> Drop for AsyncMessageGuard<'a, T, C> {
+    fn drop(&mut self) {
+        // The rejection cannot be awaited here: it is deferred to the next
+        // `AsyncConsumer::next()` call.
+        if self.state == MessageState::Unacked {
+            self.consumer.push_dropped(self.payload.clone());
+        }
+    }
+}
@@ -0,0 +1,10 @@
+//! Asynchronous counterparts of `Consumer`, `Producer` and `MessageGuard`,
+//! built on top of Redis async connections.
+
+mod consumer;
+mod message;
+mod producer;
+
+pub use consumer::AsyncConsumer;
+pub use message::AsyncMessageGuard;
+pub use producer::AsyncProducer;
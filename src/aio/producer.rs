@@ -0,0 +1,72 @@
+use crate::codec::{Codec, MsgPackCodec};
+use crate::envelope::{self, EncodeOptions};
+use crate::error::Error;
+use crate::message;
+use futures::lock::Mutex;
+use redis::{aio, AsyncCommands};
+use std::collections::HashMap;
+
+pub struct AsyncProducer<C: Codec = MsgPackCodec> {
+    queue_name: String,
+    options: EncodeOptions<C>,
+    client: Mutex<aio::Connection>,
+}
+
+impl AsyncProducer {
+    pub fn new(queue_name: String, client: aio::Connection) -> AsyncProducer {
+        AsyncProducer {
+            queue_name,
+            options: EncodeOptions::new(),
+            client: Mutex::new(client),
+        }
+    }
+}
+
+impl<C: Codec> AsyncProducer<C> {
+    /// Turn this producer into one encoding jobs with the given codec. Defaults
+    /// to `MsgPackCodec`.
+    pub fn with_codec<D: Codec>(self, codec: D) -> AsyncProducer<D> {
+        AsyncProducer {
+            queue_name: self.queue_name,
+            options: self.options.with_codec(codec),
+            client: self.client,
+        }
+    }
+
+    /// Get the options jobs are encoded with.
+    pub fn options(&self) -> &EncodeOptions<C> {
+        &self.options
+    }
+
+    /// Get a mutable reference to the options jobs are encoded with, to set
+    /// their compression, keyring or signer.
+    pub fn options_mut(&mut self) -> &mut EncodeOptions<C> {
+        &mut self.options
+    }
+
+    /// Push a new job to the source queue, wrapped in an envelope.
+    pub async fn push<T: message::MessageEncodable>(
+        &self,
+        job: T,
+    ) -> Result<(), Error> {
+        self.push_with_headers(job, HashMap::new()).await
+    }
+
+    /// Push a new job to the source queue, with the given headers in its
+    /// envelope.
+    pub async fn push_with_headers<T: message::MessageEncodable>(
+        &self,
+        job: T,
+        headers: HashMap<String, String>,
+    ) -> Result<(), Error> {
+        let encoded = envelope::encode_payload(&job, headers, &self.options)?;
+        let mut client = self.client.lock().await;
+        Ok(client.lpush(self.queue_name.as_str(), encoded).await?)
+    }
+
+    /// Get the number of remaining jobs in the queue.
+    pub async fn size(&self) -> u64 {
+        let mut client = self.client.lock().await;
+        client.llen(self.queue_name.as_str()).await.unwrap_or(0)
+    }
+}
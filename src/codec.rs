@@ -0,0 +1,219 @@
+use crate::error::Error;
+use crate::message::MessageDecodable;
+use redis::Value;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt;
+
+const MSGPACK_TAG: u8 = 0;
+#[cfg(feature = "json")]
+const JSON_TAG: u8 = 1;
+#[cfg(feature = "cbor")]
+const CBOR_TAG: u8 = 2;
+#[cfg(feature = "bincode")]
+const BINCODE_TAG: u8 = 3;
+#[cfg(feature = "protobuf")]
+const PROTOBUF_TAG: u8 = 4;
+
+/// The format used to encode messages.
+///
+/// The tag of the codec of a message is recorded in its envelope, so that a
+/// consumer can decode a queue with messages in different formats: messages
+/// with the tag of the codec of the consumer are decoded with it, and the
+/// others with the built-in codec of their tag. Bare payloads, pushed without
+/// an envelope (e.g. by producers written in other languages), are decoded
+/// with the codec of the consumer.
+///
+/// `MsgPackCodec` is the default. `JsonCodec`, `CborCodec`, `BincodeCodec` and
+/// `ProtobufCodec` are enabled by the `json`, `cbor`, `bincode` and `protobuf`
+/// cargo features. Other formats are plugged in by implementing this trait,
+/// with a tag of 128 or more: the lower ones are reserved for the built-in
+/// codecs.
+pub trait Codec: Clone + fmt::Debug + Send + Sync {
+    /// Get the tag identifying the codec in the payload.
+    fn tag(&self) -> u8;
+
+    /// Encode the value.
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, Error>;
+
+    /// Decode a value.
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, Error>;
+}
+
+/// The Msgpack codec, used by default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct MsgPackCodec;
+
+impl Codec for MsgPackCodec {
+    fn tag(&self) -> u8 {
+        MSGPACK_TAG
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, Error> {
+        Ok(rmp_serde::encode::to_vec(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, Error> {
+        Ok(rmp_serde::decode::from_slice(bytes)?)
+    }
+}
+
+/// The JSON codec.
+#[cfg(feature = "json")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct JsonCodec;
+
+#[cfg(feature = "json")]
+impl Codec for JsonCodec {
+    fn tag(&self) -> u8 {
+        JSON_TAG
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, Error> {
+        serde_json::to_vec(value).map_err(|e| Error::Encode(Box::new(e)))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, Error> {
+        serde_json::from_slice(bytes).map_err(|e| Error::Decode(Box::new(e)))
+    }
+}
+
+/// The CBOR codec.
+#[cfg(feature = "cbor")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct CborCodec;
+
+#[cfg(feature = "cbor")]
+impl Codec for CborCodec {
+    fn tag(&self) -> u8 {
+        CBOR_TAG
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, Error> {
+        serde_cbor::to_vec(value).map_err(|e| Error::Encode(Box::new(e)))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, Error> {
+        serde_cbor::from_slice(bytes).map_err(|e| Error::Decode(Box::new(e)))
+    }
+}
+
+/// The bincode codec.
+#[cfg(feature = "bincode")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct BincodeCodec;
+
+#[cfg(feature = "bincode")]
+impl Codec for BincodeCodec {
+    fn tag(&self) -> u8 {
+        BINCODE_TAG
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, Error> {
+        bincode::serialize(value).map_err(|e| Error::Encode(Box::new(e)))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, Error> {
+        bincode::deserialize(bytes).map_err(|e| Error::Decode(Box::new(e)))
+    }
+}
+
+/// The protobuf codec. It only encodes messages wrapped in `Protobuf`: serde
+/// types are rejected.
+#[cfg(feature = "protobuf")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ProtobufCodec;
+
+#[cfg(feature = "protobuf")]
+impl Codec for ProtobufCodec {
+    fn tag(&self) -> u8 {
+        PROTOBUF_TAG
+    }
+
+    fn encode<T: Serialize>(&self, _: &T) -> Result<Vec<u8>, Error> {
+        Err(Error::Encode("expected a protobuf message".into()))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, _: &[u8]) -> Result<T, Error> {
+        Err(Error::Decode("expected a protobuf message".into()))
+    }
+}
+
+/// Decode a message whose envelope records the given codec tag, with the given
+/// codec if it has this tag or with the built-in codec of the tag otherwise.
+pub(crate) fn decode_tagged<T: MessageDecodable, C: Codec>(
+    value: &Value,
+    tag: u8,
+    codec: &C,
+) -> Result<T, Error> {
+    if tag == codec.tag() {
+        return T::decode_message_with(value, codec);
+    }
+    match tag {
+        MSGPACK_TAG => T::decode_message_with(value, &MsgPackCodec),
+        #[cfg(feature = "json")]
+        JSON_TAG => T::decode_message_with(value, &JsonCodec),
+        #[cfg(feature = "cbor")]
+        CBOR_TAG => T::decode_message_with(value, &CborCodec),
+        #[cfg(feature = "bincode")]
+        BINCODE_TAG => T::decode_message_with(value, &BincodeCodec),
+        #[cfg(feature = "protobuf")]
+        PROTOBUF_TAG => T::decode_message_with(value, &ProtobufCodec),
+        tag => Err(Error::Decode(format!("unknown codec tag {}", tag).into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<C: Codec>(codec: C) {
+        let encoded = codec.encode(&(42u64, "answer")).unwrap();
+        let decoded: (u64, String) = codec.decode(&encoded).unwrap();
+        assert_eq!((42, "answer".to_string()), decoded);
+        let decoded: (u64, String) =
+            decode_tagged(&Value::Data(encoded), codec.tag(), &MsgPackCodec)
+                .unwrap();
+        assert_eq!((42, "answer".to_string()), decoded);
+    }
+
+    #[test]
+    fn built_in_codecs_round_trip() {
+        round_trip(MsgPackCodec);
+        #[cfg(feature = "json")]
+        round_trip(JsonCodec);
+        #[cfg(feature = "cbor")]
+        round_trip(CborCodec);
+        #[cfg(feature = "bincode")]
+        round_trip(BincodeCodec);
+    }
+
+    #[derive(Clone, Debug)]
+    struct Reversed;
+
+    impl Codec for Reversed {
+        fn tag(&self) -> u8 {
+            200
+        }
+
+        fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, Error> {
+            let mut bytes = MsgPackCodec.encode(value)?;
+            bytes.reverse();
+            Ok(bytes)
+        }
+
+        fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, Error> {
+            let mut bytes = bytes.to_vec();
+            bytes.reverse();
+            MsgPackCodec.decode(&bytes)
+        }
+    }
+
+    #[test]
+    fn custom_codecs_decode_their_tag() {
+        let value = Value::Data(Reversed.encode(&42u64).unwrap());
+        assert_eq!(42, decode_tagged::<u64, _>(&value, 200, &Reversed).unwrap());
+        let err = decode_tagged::<u64, _>(&value, 200, &MsgPackCodec).unwrap_err();
+        assert!(err.is_poison());
+    }
+}
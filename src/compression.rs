@@ -0,0 +1,132 @@
+use crate::error::Error;
+
+/// Size in bytes from which producers compress the encoded messages, unless
+/// configured otherwise.
+pub(crate) const DEFAULT_THRESHOLD: usize = 1024;
+
+/// The algorithm used to compress large messages.
+///
+/// Producers compress the encoded messages bigger than their compression
+/// threshold, and record the algorithm in the envelope so that consumers can
+/// decompress them transparently. Messages pushed without compression, or by
+/// older producers, are decoded unchanged.
+///
+/// Each algorithm is enabled by the cargo feature with the same name: `zstd`,
+/// `lz4` and `gzip`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Compression {
+    #[cfg(feature = "zstd")]
+    Zstd,
+    #[cfg(feature = "lz4")]
+    Lz4,
+    #[cfg(feature = "gzip")]
+    Gzip,
+}
+
+// Without any algorithm enabled, the enum has no variants and the arguments
+// of the methods are never used.
+#[cfg_attr(
+    not(any(feature = "zstd", feature = "lz4", feature = "gzip")),
+    allow(unused_variables)
+)]
+impl Compression {
+    /// Get the tag identifying the algorithm in the payload. 0 means that the
+    /// message is not compressed.
+    pub fn tag(self) -> u8 {
+        match self {
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => 1,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => 2,
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => 3,
+        }
+    }
+
+    /// Get the algorithm with the given tag, if it is enabled.
+    pub fn from_tag(tag: u8) -> Option<Compression> {
+        match tag {
+            #[cfg(feature = "zstd")]
+            1 => Some(Compression::Zstd),
+            #[cfg(feature = "lz4")]
+            2 => Some(Compression::Lz4),
+            #[cfg(feature = "gzip")]
+            3 => Some(Compression::Gzip),
+            _ => None,
+        }
+    }
+
+    /// Compress the encoded message.
+    pub fn compress(self, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                zstd::encode_all(bytes, 0).map_err(|e| Error::Encode(Box::new(e)))
+            }
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(bytes)),
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => {
+                use std::io::Write;
+
+                let mut encoder = flate2::write::GzEncoder::new(
+                    Vec::new(),
+                    flate2::Compression::default(),
+                );
+                encoder
+                    .write_all(bytes)
+                    .and_then(|_| encoder.finish())
+                    .map_err(|e| Error::Encode(Box::new(e)))
+            }
+        }
+    }
+
+    /// Decompress an encoded message.
+    pub fn decompress(self, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                zstd::decode_all(bytes).map_err(|e| Error::Decode(Box::new(e)))
+            }
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => lz4_flex::decompress_size_prepended(bytes)
+                .map_err(|e| Error::Decode(Box::new(e))),
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => {
+                use std::io::Read;
+
+                let mut decompressed = Vec::new();
+                flate2::read::GzDecoder::new(bytes)
+                    .read_to_end(&mut decompressed)
+                    .map_err(|e| Error::Decode(Box::new(e)))?;
+                Ok(decompressed)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let algorithms: &[Compression] = &[
+            #[cfg(feature = "zstd")]
+            Compression::Zstd,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4,
+            #[cfg(feature = "gzip")]
+            Compression::Gzip,
+        ];
+        let bytes = vec![42u8; 4096];
+        for algorithm in algorithms.iter() {
+            assert_eq!(Some(*algorithm), Compression::from_tag(algorithm.tag()));
+            let compressed = algorithm.compress(&bytes).unwrap();
+            assert!(compressed.len() < bytes.len());
+            assert_eq!(bytes, algorithm.decompress(&compressed).unwrap());
+            assert!(algorithm.decompress(&[1, 2, 3]).unwrap_err().is_poison());
+        }
+        assert_eq!(None, Compression::from_tag(0));
+    }
+}
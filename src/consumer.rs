@@ -1,6 +1,21 @@
+use crate::codec::{Codec, MsgPackCodec};
+use crate::dead_letter::DeadLetter;
+use crate::delayed;
+use crate::encryption::Keyring;
+use crate::envelope;
+use crate::error::Error;
+use crate::heartbeat::{self, Heartbeat};
 use crate::message;
-use redis::{from_redis_value, Commands, RedisResult, Value};
-use std::cell::{Cell, RefCell};
+use crate::priority::{self, PriorityMode};
+use crate::retry::RetryPolicy;
+use crate::signing::{SignaturePolicy, Signer};
+use redis::{Commands, RedisResult, Script, Value};
+use std::any::Any;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 pub const CONSUMERS_KEY: &str = "orizuru:consumers";
@@ -8,17 +23,137 @@ pub const HEARTBEAT_KEY: &str = "orizuru:consumers:{consumer}:heartbeat";
 pub const HEARTBEATS_KEY: &str = "orizuru:heartbeats";
 pub const PROCESSING_QUEUE_KEY: &str = "orizuru:consumers:{consumer}:processing";
 pub const UNACKED_QUEUE_KEY: &str = "orizuru:consumers:{consumer}:unacked";
+pub const SOURCES_KEY: &str = "orizuru:consumers:{consumer}:sources";
+pub const DEADLINES_KEY: &str = "orizuru:consumers:{consumer}:deadlines";
+pub const INFLIGHT_KEY: &str = "orizuru:consumers:{consumer}:inflight";
+pub const REJECTIONS_KEY: &str = "orizuru:consumers:{consumer}:rejections";
+pub const ORIGINS_KEY: &str = "orizuru:consumers:{consumer}:origins";
+pub const DELAYED_QUEUE_KEY: &str = "orizuru:queues:{queue}:delayed";
+pub const DELAYED_QUEUES_KEY: &str = "orizuru:delayed";
+pub const DEAD_LETTER_QUEUE_KEY: &str = "orizuru:queues:{queue}:dead";
 
-pub struct Consumer {
+/// Maximum number of due messages moved to the source queue by each call to
+/// `Consumer::next()`.
+const PROMOTE_LIMIT: u64 = 100;
+
+/// How long `Consumer::next()` waits before polling the source queues again
+/// when they are all empty, if it cannot block on a single queue.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How many seconds `Consumer::next()` and `AsyncConsumer::next()` block at a
+/// time without a block timeout, so that they notice when the consumer is
+/// stopped.
+pub(crate) const STOP_CHECK_SECS: usize = 1;
+
+/// The order in which a consumer with several source queues fetches from
+/// them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SourceOrder {
+    /// Always fetch from the first non-empty queue, in the order they were
+    /// given.
+    Strict,
+    /// Start from a different queue on each fetch, so that every queue gets
+    /// its turn.
+    RoundRobin,
+}
+
+pub struct Consumer<C: Codec = MsgPackCodec> {
     name: String,
-    source_queue_name: String,
+    source_queue_names: Vec<String>,
     processing_queue_name: String,
     unacked_queue_name: String,
+    rejections_key: String,
+    origins_key: String,
+    sources_key: String,
+    deadlines_key: String,
+    inflight_key: String,
+    delayed_queue_name: String,
+    dead_letter_queue_name: String,
     consumers_key: String,
     heartbeat_key: String,
     heartbeats_key: String,
-    stopped: Cell<bool>,
-    client: RefCell<redis::Connection>,
+    block_timeout: Duration,
+    retry_policy: RetryPolicy,
+    visibility_timeout: Option<Duration>,
+    priority_mode: PriorityMode,
+    source_order: SourceOrder,
+    codec: C,
+    keyring: Option<Keyring>,
+    signer: Option<Signer>,
+    signature_policy: SignaturePolicy,
+    next_source: AtomicUsize,
+    // Whether each source queue is known to have had delayed messages.
+    delayed_sources: Vec<AtomicBool>,
+    fetching: AtomicUsize,
+    promote_script: Script,
+    fetch_script: Script,
+    stopped: Arc<AtomicBool>,
+    heartbeat: Mutex<Option<Heartbeat>>,
+    client: Mutex<redis::Connection>,
+    fetch_client: Option<Mutex<redis::Connection>>,
+}
+
+/// A handle that can stop a consumer from another thread, e.g. from a signal
+/// handler.
+#[derive(Clone, Debug)]
+pub struct StopHandle {
+    stopped: Arc<AtomicBool>,
+}
+
+impl StopHandle {
+    pub(crate) fn new(stopped: Arc<AtomicBool>) -> StopHandle {
+        StopHandle { stopped }
+    }
+
+    /// Stop the consumer this handle was obtained from.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    /// Check if the consumer this handle was obtained from is stopped.
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+}
+
+/// Counts a call to `Consumer::next()` as in flight until it is dropped.
+struct InFlight<'a>(&'a AtomicUsize);
+
+impl<'a> InFlight<'a> {
+    fn new(count: &'a AtomicUsize) -> InFlight<'a> {
+        count.fetch_add(1, Ordering::SeqCst);
+        InFlight(count)
+    }
+}
+
+impl<'a> Drop for InFlight<'a> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Lock a connection, recovering it if another thread panicked while holding
+/// the lock.
+pub(crate) fn lock<T>(client: &Mutex<T>) -> MutexGuard<'_, T> {
+    client.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Convert a block timeout into the number of seconds expected by BRPOPLPUSH,
+/// where zero means blocking indefinitely.
+pub(crate) fn block_timeout_secs(timeout: Duration) -> usize {
+    if timeout == Duration::from_secs(0) {
+        return 0;
+    }
+    let secs = timeout.as_secs() + u64::from(timeout.subsec_nanos() > 0);
+    secs as usize
+}
+
+/// Get the current time in milliseconds since the Unix epoch.
+pub(crate) fn now_millis() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_millis() as u64,
+        Err(_) => 0,
+    }
 }
 
 impl Consumer {
@@ -27,48 +162,315 @@ impl Consumer {
         source_queue_name: String,
         client: redis::Connection,
     ) -> Consumer {
+        Consumer::with_source_queues(name, vec![source_queue_name], client)
+    }
+
+    /// Create a consumer fetching from several source queues, in the order
+    /// set with `Consumer::set_source_order()`.
+    ///
+    /// The first queue is the main source queue: the delayed and dead-letter
+    /// queue getters refer to it. Retries and dead letters still go to the
+    /// queues of the source each message came from, which is recorded in the
+    /// origins hash (see `Consumer::origins_key()`) for the garbage collector
+    /// to requeue it there.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `source_queue_names` is empty.
+    pub fn with_source_queues(
+        name: String,
+        source_queue_names: Vec<String>,
+        client: redis::Connection,
+    ) -> Consumer {
+        assert!(
+            !source_queue_names.is_empty(),
+            "a consumer needs at least one source queue"
+        );
+        let source_queue_name = &source_queue_names[0];
         let processing_queue_name =
             PROCESSING_QUEUE_KEY.replace("{consumer}", name.as_str());
         let unacked_queue_name =
             UNACKED_QUEUE_KEY.replace("{consumer}", name.as_str());
+        let rejections_key = REJECTIONS_KEY.replace("{consumer}", name.as_str());
+        let origins_key = ORIGINS_KEY.replace("{consumer}", name.as_str());
+        let sources_key = SOURCES_KEY.replace("{consumer}", name.as_str());
+        let deadlines_key = DEADLINES_KEY.replace("{consumer}", name.as_str());
+        let inflight_key = INFLIGHT_KEY.replace("{consumer}", name.as_str());
+        let delayed_queue_name =
+            DELAYED_QUEUE_KEY.replace("{queue}", source_queue_name.as_str());
+        let dead_letter_queue_name =
+            DEAD_LETTER_QUEUE_KEY.replace("{queue}", source_queue_name.as_str());
         let heartbeat_key = HEARTBEAT_KEY.replace("{consumer}", name.as_str());
+        let delayed_sources = source_queue_names
+            .iter()
+            .map(|_| AtomicBool::new(false))
+            .collect();
 
         Consumer {
             name,
-            source_queue_name,
+            source_queue_names,
             processing_queue_name,
             unacked_queue_name,
+            rejections_key,
+            origins_key,
+            sources_key,
+            deadlines_key,
+            inflight_key,
+            delayed_queue_name,
+            dead_letter_queue_name,
             consumers_key: CONSUMERS_KEY.into(),
             heartbeat_key,
             heartbeats_key: HEARTBEATS_KEY.into(),
-            client: RefCell::new(client),
-            stopped: Cell::new(false),
+            block_timeout: Duration::from_secs(0),
+            retry_policy: RetryPolicy::default(),
+            visibility_timeout: None,
+            priority_mode: PriorityMode::Disabled,
+            source_order: SourceOrder::Strict,
+            codec: MsgPackCodec,
+            keyring: None,
+            signer: None,
+            signature_policy: SignaturePolicy::DeadLetter,
+            next_source: AtomicUsize::new(0),
+            delayed_sources,
+            fetching: AtomicUsize::new(0),
+            promote_script: Script::new(delayed::PROMOTE_SCRIPT),
+            fetch_script: Script::new(priority::FETCH_SCRIPT),
+            client: Mutex::new(client),
+            fetch_client: None,
+            stopped: Arc::new(AtomicBool::new(false)),
+            heartbeat: Mutex::new(None),
         }
     }
+}
 
+impl<C: Codec> Consumer<C> {
     /// Register this consumer to enable automatic discovery by the garbage
     /// collector.
-    pub fn register(&self) -> RedisResult<Value> {
-        self.client
-            .borrow_mut()
-            .sadd(CONSUMERS_KEY, self.name.as_str())
+    ///
+    /// The source queues are recorded as well, so that the garbage collector
+    /// can requeue the messages of the consumer if it dies.
+    pub fn register(&self) -> Result<Value, Error> {
+        let (added,): (Value,) = redis::pipe()
+            .atomic()
+            .cmd("SADD")
+            .arg(CONSUMERS_KEY)
+            .arg(self.name.as_str())
+            .cmd("DEL")
+            .arg(self.sources_key.as_str())
+            .ignore()
+            .cmd("RPUSH")
+            .arg(self.sources_key.as_str())
+            .arg(&self.source_queue_names)
+            .ignore()
+            .query(&mut *lock(&self.client))?;
+        Ok(added)
     }
 
-    pub fn deregister(&self) -> RedisResult<Value> {
-        self.client
-            .borrow_mut()
-            .srem(CONSUMERS_KEY, self.name.as_str())
+    /// Deregister this consumer, forgetting its source queues and the reasons
+    /// of its rejections.
+    pub fn deregister(&self) -> Result<Value, Error> {
+        let (removed,): (Value,) = redis::pipe()
+            .atomic()
+            .cmd("SREM")
+            .arg(CONSUMERS_KEY)
+            .arg(self.name.as_str())
+            .cmd("DEL")
+            .arg(self.sources_key.as_str())
+            .arg(self.rejections_key.as_str())
+            .ignore()
+            .cmd("HDEL")
+            .arg(self.heartbeats_key.as_str())
+            .arg(self.name.as_str())
+            .ignore()
+            .query(&mut *lock(&self.client))?;
+        Ok(removed)
     }
 
     /// Stop processing the queue.
-    /// The next `Consumer::next()` call will return `None`.
+    /// The next `Consumer::next()` call will return `None`. A call that is
+    /// already waiting for a job returns `None` within the block timeout, and
+    /// puts back the message it fetched in the meantime, if any.
     pub fn stop(&self) {
-        self.stopped.set(true);
+        self.stopped.store(true, Ordering::SeqCst);
     }
 
     /// Check if queue processing is stopped.
     pub fn is_stopped(&self) -> bool {
-        self.stopped.get()
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Check if a call to `Consumer::next()` is in flight.
+    pub(crate) fn is_fetching(&self) -> bool {
+        self.fetching.load(Ordering::SeqCst) > 0
+    }
+
+    /// Get a handle that can stop this consumer from another thread.
+    pub fn stop_handle(&self) -> StopHandle {
+        StopHandle::new(self.stopped.clone())
+    }
+
+    /// Share the stop flag of the given handle, e.g. to stop several consumers
+    /// at once.
+    pub(crate) fn set_stop_handle(&mut self, handle: StopHandle) {
+        self.stopped = handle.stopped;
+    }
+
+    /// Set how long `Consumer::next()` blocks waiting for a job before checking
+    /// again whether the consumer has been stopped. Redis only supports whole
+    /// seconds, so the timeout is rounded up. A zero timeout (the default)
+    /// waits indefinitely, checking every second.
+    ///
+    /// Without a fetch connection (see `Consumer::set_fetch_connection()`),
+    /// the shared connection is blocked for this long, so messages acknowledged
+    /// from other threads in the meantime wait for it. Consumers with several
+    /// source queues or priorities poll every 100 milliseconds instead.
+    pub fn set_block_timeout(&mut self, timeout: Duration) {
+        self.block_timeout = timeout;
+    }
+
+    /// Get the block timeout.
+    pub fn block_timeout(&self) -> Duration {
+        self.block_timeout
+    }
+
+    /// Set the policy used by `MessageGuard::retry()`.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry_policy = policy;
+    }
+
+    /// Get the retry policy.
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
+    }
+
+    /// Set how long a delivered message may stay in the *processing* queue
+    /// before the garbage collector moves it back to the source queue, unless
+    /// its deadline is pushed out with `MessageGuard::extend()`. Defaults to
+    /// `None`, i.e. messages are never requeued while the consumer is alive.
+    pub fn set_visibility_timeout(&mut self, timeout: Option<Duration>) {
+        self.visibility_timeout = timeout;
+    }
+
+    /// Get the visibility timeout.
+    pub fn visibility_timeout(&self) -> Option<Duration> {
+        self.visibility_timeout
+    }
+
+    /// Set how the next message is picked among the priority lists of the
+    /// source queue (see `PriorityProducer`). Defaults to
+    /// `PriorityMode::Disabled`, which only fetches from the source queue.
+    ///
+    /// Redis cannot block on several lists at once, so when priorities are
+    /// enabled the lists are polled every 100 milliseconds while they are all
+    /// empty, instead of blocking for the block timeout.
+    pub fn set_priority_mode(&mut self, mode: PriorityMode) {
+        self.priority_mode = mode;
+    }
+
+    /// Get the priority mode.
+    pub fn priority_mode(&self) -> PriorityMode {
+        self.priority_mode
+    }
+
+    /// Set the order in which the source queues are fetched from. Defaults to
+    /// `SourceOrder::Strict`.
+    ///
+    /// As with priorities, a consumer with several source queues polls them
+    /// every 100 milliseconds while they are all empty.
+    pub fn set_source_order(&mut self, order: SourceOrder) {
+        self.source_order = order;
+    }
+
+    /// Get the source order.
+    pub fn source_order(&self) -> SourceOrder {
+        self.source_order
+    }
+
+    /// Turn this consumer into one decoding bare payloads, pushed without an
+    /// envelope, with the given codec. Defaults to `MsgPackCodec`.
+    ///
+    /// Messages with an envelope are decoded with the codec recorded in it:
+    /// the given one if it has the same tag, or the built-in codec of the tag.
+    pub fn with_codec<D: Codec>(self, codec: D) -> Consumer<D> {
+        Consumer {
+            name: self.name,
+            source_queue_names: self.source_queue_names,
+            processing_queue_name: self.processing_queue_name,
+            unacked_queue_name: self.unacked_queue_name,
+            rejections_key: self.rejections_key,
+            origins_key: self.origins_key,
+            sources_key: self.sources_key,
+            deadlines_key: self.deadlines_key,
+            inflight_key: self.inflight_key,
+            delayed_queue_name: self.delayed_queue_name,
+            dead_letter_queue_name: self.dead_letter_queue_name,
+            consumers_key: self.consumers_key,
+            heartbeat_key: self.heartbeat_key,
+            heartbeats_key: self.heartbeats_key,
+            block_timeout: self.block_timeout,
+            retry_policy: self.retry_policy,
+            visibility_timeout: self.visibility_timeout,
+            priority_mode: self.priority_mode,
+            source_order: self.source_order,
+            codec,
+            keyring: self.keyring,
+            signer: self.signer,
+            signature_policy: self.signature_policy,
+            next_source: self.next_source,
+            delayed_sources: self.delayed_sources,
+            fetching: self.fetching,
+            promote_script: self.promote_script,
+            fetch_script: self.fetch_script,
+            stopped: self.stopped,
+            heartbeat: self.heartbeat,
+            client: self.client,
+            fetch_client: self.fetch_client,
+        }
+    }
+
+    /// Get the codec.
+    pub fn codec(&self) -> &C {
+        &self.codec
+    }
+
+    /// Set the keyring encrypted messages are decrypted with.
+    ///
+    /// Messages that cannot be decrypted, because their key is unknown or
+    /// they fail authentication, are moved to the dead-letter queue and
+    /// reported with `Error::Decrypt`.
+    pub fn set_keyring(&mut self, keyring: Keyring) {
+        self.keyring = Some(keyring);
+    }
+
+    /// Get the keyring.
+    pub fn keyring(&self) -> Option<&Keyring> {
+        self.keyring.as_ref()
+    }
+
+    /// Set the signer messages are verified with, before being decoded.
+    ///
+    /// Messages that are unsigned or signed with an unknown key are handled
+    /// according to the signature policy and reported with
+    /// `Error::Signature`. Retried messages are signed again with the primary
+    /// key.
+    pub fn set_signer(&mut self, signer: Signer) {
+        self.signer = Some(signer);
+    }
+
+    /// Get the signer.
+    pub fn signer(&self) -> Option<&Signer> {
+        self.signer.as_ref()
+    }
+
+    /// Set what to do with the messages that fail verification. Defaults to
+    /// `SignaturePolicy::DeadLetter`.
+    pub fn set_signature_policy(&mut self, policy: SignaturePolicy) {
+        self.signature_policy = policy;
+    }
+
+    /// Get the signature policy.
+    pub fn signature_policy(&self) -> SignaturePolicy {
+        self.signature_policy
     }
 
     /// Get the name of the consumer.
@@ -91,9 +493,14 @@ impl Consumer {
         &self.heartbeats_key
     }
 
-    /// Get the source queue name.
+    /// Get the main source queue name.
     pub fn source_queue(&self) -> &str {
-        &self.source_queue_name
+        &self.source_queue_names[0]
+    }
+
+    /// Get the names of all the source queues.
+    pub fn source_queues(&self) -> &[String] {
+        &self.source_queue_names
     }
 
     /// Get the processing queue name.
@@ -106,80 +513,543 @@ impl Consumer {
         &self.unacked_queue_name
     }
 
-    /// Get the number of remaining jobs in the queue.
+    /// Get the sources key, i.e. the list of the source queues recorded when
+    /// the consumer registers.
+    pub fn sources_key(&self) -> &str {
+        &self.sources_key
+    }
+
+    /// Get the deadlines key, i.e. the sorted set of the messages being
+    /// processed scored by their visibility deadline.
+    ///
+    /// Messages are identified by the id of their envelope, so that identical
+    /// payloads get their own deadline, or by their payload if they have none.
+    pub fn deadlines_key(&self) -> &str {
+        &self.deadlines_key
+    }
+
+    /// Get the inflight key, i.e. the hash that maps the members of the
+    /// deadlines sorted set to the payloads of the messages being processed.
+    pub fn inflight_key(&self) -> &str {
+        &self.inflight_key
+    }
+
+    /// Get the rejections key, i.e. the hash that maps rejected payloads to
+    /// the reason they were rejected. Entries are removed when the garbage
+    /// collector moves the messages out of the *unack* queue, and when the
+    /// consumer deregisters.
+    pub fn rejections_key(&self) -> &str {
+        &self.rejections_key
+    }
+
+    /// Get the origins key, i.e. the hash that maps the payloads fetched by a
+    /// consumer with several source queues to the queue they came from.
+    ///
+    /// Messages without an origin belong to the main source queue.
+    pub fn origins_key(&self) -> &str {
+        &self.origins_key
+    }
+
+    /// Get the reason why the given payload was rejected, if it was recorded.
+    pub fn rejection_reason(
+        &self,
+        payload: &[u8],
+    ) -> Result<Option<String>, Error> {
+        Ok(lock(&self.client).hget(self.rejections_key.as_str(), payload)?)
+    }
+
+    /// Get the delayed queue name, i.e. the sorted set holding the messages
+    /// scheduled for a retry.
+    pub fn delayed_queue(&self) -> &str {
+        &self.delayed_queue_name
+    }
+
+    /// Get the dead-letter queue name.
+    pub fn dead_letter_queue(&self) -> &str {
+        &self.dead_letter_queue_name
+    }
+
+    /// Get the connection used by this consumer.
+    pub fn client(&self) -> &Mutex<redis::Connection> {
+        &self.client
+    }
+
+    /// Set a dedicated connection to block on while waiting for the next
+    /// message.
+    ///
+    /// Without one, `Consumer::next()` blocks the connection shared with the
+    /// message guards for up to the block timeout, so that messages
+    /// acknowledged from other threads wait for it. Consumers with several
+    /// source queues or priorities always poll.
+    pub fn set_fetch_connection(&mut self, client: redis::Connection) {
+        self.fetch_client = Some(Mutex::new(client));
+    }
+
+    /// Get the number of remaining jobs in the source queues.
     pub fn size(&self) -> u64 {
-        self.client
-            .borrow_mut()
-            .llen(self.source_queue_name.as_str())
-            .unwrap_or(0)
+        let mut client = lock(&self.client);
+        self.source_queue_names
+            .iter()
+            .map(|q| client.llen(q.as_str()).unwrap_or(0))
+            .sum()
     }
 
     pub fn heartbeat(&self, ttl: Duration) -> u128 {
-        let now = SystemTime::now();
-        let ts = match now.duration_since(UNIX_EPOCH) {
-            Ok(d) => d.as_millis(),
-            Err(_) => 0,
-        };
-        let _: RedisResult<()> = redis::pipe()
-            .cmd("HSET")
-            .arg(self.heartbeats_key.as_str())
-            .arg(self.name.as_str())
-            .arg(ts.to_string())
-            .ignore()
-            .cmd("SET")
-            .arg(self.heartbeat_key.as_str())
-            .arg(ts.to_string())
-            .arg("PX")
-            .arg(ttl.as_millis().to_string())
-            .ignore()
-            .query(&mut *self.client.borrow_mut());
-
+        let (ts, _) = heartbeat::beat(
+            &mut *lock(&self.client),
+            &self.heartbeats_key,
+            &self.heartbeat_key,
+            &self.name,
+            ttl,
+        );
         ts
     }
 
+    /// Send heartbeats with the given TTL every `interval` from a background
+    /// thread, using the given connection, until the consumer is stopped or
+    /// dropped, or `Consumer::stop_heartbeat()` is called.
+    ///
+    /// The TTL should be a few times the interval, so that a slow heartbeat
+    /// does not get the consumer collected as dead. Calling this method again
+    /// replaces the running heartbeat.
+    pub fn start_heartbeat(
+        &self,
+        client: redis::Connection,
+        interval: Duration,
+        ttl: Duration,
+    ) {
+        let heartbeat = Heartbeat::start(
+            client,
+            self.heartbeats_key.clone(),
+            self.heartbeat_key.clone(),
+            self.name.clone(),
+            interval,
+            ttl,
+            self.stopped.clone(),
+        );
+        // The previous heartbeat, if any, is stopped outside of the lock.
+        let previous = lock(&self.heartbeat).replace(heartbeat);
+        drop(previous);
+    }
+
+    /// Stop the background heartbeat, if it is running.
+    pub fn stop_heartbeat(&self) {
+        let heartbeat = lock(&self.heartbeat).take();
+        drop(heartbeat);
+    }
+
     /// Grab the next job from the queue.
     ///
     /// This method blocks and waits until a new job is available. It returns
-    /// None if the consumer has been stopped (with the stop() method).
-    /// Otherwise it returns a RedisResult value that may wrap the message.
+    /// None if the consumer has been stopped (with the stop() method), checking
+    /// again every time the block timeout expires, or every 100 milliseconds
+    /// for consumers with several source queues or priorities, which poll.
+    /// Otherwise it returns a Result value that may wrap the message.
+    ///
+    /// Retries whose delay has expired are moved back to the source queue
+    /// before waiting, and the wait is shortened so that the next one is not
+    /// late.
     pub fn next<T: message::MessageDecodable>(
         &self,
-    ) -> Option<Result<message::MessageGuard<T>, &'static str>> {
+    ) -> Option<Result<message::MessageGuard<'_, T, C>, Error>> {
+        self.next_message(false)
+    }
+
+    /// Grab the next job from the queue, rejecting the ones that cannot be
+    /// decoded if `reject_poison` is set, with the error as the reason.
+    fn next_message<T: message::MessageDecodable>(
+        &self,
+        reject_poison: bool,
+    ) -> Option<Result<message::MessageGuard<'_, T, C>, Error>> {
+        let processing = &self.processing_queue_name[..];
+        // Counted before checking the stop flag, so that a shutdown that sees
+        // no call in flight after stopping the consumer cannot miss a fetch.
+        let _in_flight = InFlight::new(&self.fetching);
+
+        let (source, queue, v) = loop {
+            if self.is_stopped() {
+                return None;
+            }
+
+            let now = now_millis();
+            let next_due = match self.promote_due(now) {
+                Ok(next_due) => next_due,
+                Err(e) => return Some(Err(e.into())),
+            };
+
+            // A single source queue is fetched with a blocking call, on the
+            // fetch connection if there is one. Otherwise the shared connection
+            // is blocked for the bounded block timeout only, so that the
+            // acknowledgements of other threads wait for it at most.
+            if let ([source], PriorityMode::Disabled) =
+                (&self.source_queue_names[..], self.priority_mode)
+            {
+                let fetch_client =
+                    self.fetch_client.as_ref().unwrap_or(&self.client);
+                let mut timeout = match block_timeout_secs(self.block_timeout) {
+                    0 => STOP_CHECK_SECS,
+                    timeout => timeout,
+                };
+                if let Some(due) = next_due {
+                    let wait = Duration::from_millis(due.saturating_sub(now));
+                    timeout = timeout.min(block_timeout_secs(wait).max(1));
+                }
+                let res = lock(fetch_client).brpoplpush(
+                    source.as_str(),
+                    processing,
+                    timeout,
+                );
+                match res {
+                    Ok(Value::Nil) => continue,
+                    Ok(v) => break (source, source.clone(), v),
+                    Err(e) => return Some(Err(e.into())),
+                }
+            }
+
+            let (mut queues, sources): (Vec<_>, Vec<_>) =
+                self.fetch_order().into_iter().unzip();
+            // Messages of a single source queue need no origin.
+            let origins: Vec<&str> = sources
+                .iter()
+                .map(|&i| self.source_queue_names[i].as_str())
+                .collect();
+            let res = priority::fetch(
+                &mut *lock(&self.client),
+                &self.fetch_script,
+                &queues,
+                processing,
+                &self.origins_key,
+                Some(&origins[..]).filter(|_| self.source_queue_names.len() > 1),
+            );
+            match res {
+                Ok(None) => thread::sleep(POLL_INTERVAL),
+                Ok(Some((i, v))) => {
+                    let source = &self.source_queue_names[sources[i]];
+                    break (source, queues.swap_remove(i), v);
+                }
+                Err(e) => return Some(Err(e.into())),
+            }
+        };
+
+        let payload = match v {
+            Value::Data(payload) => payload,
+            v => return Some(Err(Error::UnexpectedReply(v))),
+        };
+
+        // The shutdown may already have swept the processing queue.
         if self.is_stopped() {
-            return None;
+            return match self.unfetch(&queue, &payload) {
+                Ok(()) => None,
+                Err(e) => Some(Err(e)),
+            };
+        }
+
+        if let Some(timeout) = self.visibility_timeout {
+            let deadline = now_millis() + timeout.as_millis() as u64;
+            let envelope = envelope::Envelope::parse(&payload)
+                .ok()
+                .and_then(|(envelope, _)| envelope);
+            let member = envelope::deadline_member(envelope.as_ref(), &payload);
+            let res: RedisResult<()> = redis::pipe()
+                .atomic()
+                .cmd("ZADD")
+                .arg(self.deadlines_key.as_str())
+                .arg(deadline)
+                .arg(member)
+                .ignore()
+                .cmd("HSET")
+                .arg(self.inflight_key.as_str())
+                .arg(member)
+                .arg(payload.as_slice())
+                .ignore()
+                .query(&mut *lock(&self.client));
+            if let Err(e) = res {
+                return Some(Err(e.into()));
+            }
+        }
+
+        if let Some(Err(e)) = self.signer().map(|s| s.verify(&payload)) {
+            if let Err(e) =
+                self.discard_unverified(source, payload, &e.to_string())
+            {
+                return Some(Err(e));
+            }
+            return Some(Err(e));
         }
 
-        let v;
-        {
-            let source = &self.source_queue_name[..];
-            let processing = &self.processing_queue_name[..];
-
-            v = match self.client.borrow_mut().brpoplpush(source, processing, 0) {
-                Ok(v) => v,
-                Err(_) => {
-                    return Some(Err(
-                        "failed to fetch next message with brpoplpush",
-                    ));
+        match envelope::decode_payload(&payload, &self.codec, self.keyring()) {
+            Err(e) if e.is_decrypt() => {
+                let reason = e.to_string();
+                if let Err(e) = self.dead_letter_payload(source, payload, &reason)
+                {
+                    return Some(Err(e));
                 }
-            };
+                Some(Err(e))
+            }
+            Err(e) if reject_poison && e.is_poison() => {
+                if let Err(e) = self.reject_payload(&payload, &e.to_string()) {
+                    return Some(Err(e));
+                }
+                Some(Err(e))
+            }
+            Err(e) => Some(Err(e)),
+            Ok((message, envelope)) => {
+                let mut guard: message::MessageGuard<T, C> =
+                    message::MessageGuard::new(message, payload, envelope, self);
+                guard.set_source_queue(source);
+                Some(Ok(guard))
+            }
+        }
+    }
+
+    /// Move the due retries of the source queues back to them.
+    ///
+    /// Returns the due time of the next retry, if any.
+    ///
+    /// Source queues that never had delayed messages are not in the set of
+    /// delayed queues, and are skipped. The set is never pruned, so the ones
+    /// found in it are not looked up again.
+    fn promote_due(&self, now: u64) -> RedisResult<Option<u64>> {
+        let mut client = lock(&self.client);
+        let unknown: Vec<usize> = (0..self.source_queue_names.len())
+            .filter(|&i| !self.delayed_sources[i].load(Ordering::Relaxed))
+            .collect();
+        if !unknown.is_empty() {
+            let mut pipe = redis::pipe();
+            for &i in &unknown {
+                pipe.cmd("SISMEMBER")
+                    .arg(DELAYED_QUEUES_KEY)
+                    .arg(self.source_queue_names[i].as_str());
+            }
+            let delayed: Vec<bool> = pipe.query(&mut *client)?;
+            for (i, delayed) in unknown.into_iter().zip(delayed) {
+                if delayed {
+                    self.delayed_sources[i].store(true, Ordering::Relaxed);
+                }
+            }
+        }
+
+        let mut next_due: Option<u64> = None;
+        let sources = self.source_queue_names.iter().zip(&self.delayed_sources);
+        for (source, delayed) in sources {
+            if !delayed.load(Ordering::Relaxed) {
+                continue;
+            }
+            let delayed_queue_name =
+                DELAYED_QUEUE_KEY.replace("{queue}", source.as_str());
+            let (_, due) = delayed::promote(
+                &mut *client,
+                &self.promote_script,
+                &delayed_queue_name,
+                source,
+                now,
+                PROMOTE_LIMIT,
+            )?;
+            if let Some(due) = due {
+                next_due = Some(next_due.map_or(due, |d| d.min(due)));
+            }
+        }
+        Ok(next_due)
+    }
+
+    /// Move a payload fetched from the given queue back to its end, as if it
+    /// had never been fetched.
+    fn unfetch(&self, queue: &str, payload: &[u8]) -> Result<(), Error> {
+        Ok(redis::pipe()
+            .atomic()
+            .cmd("LREM")
+            .arg(self.processing_queue_name.as_str())
+            .arg(1)
+            .arg(payload)
+            .ignore()
+            .cmd("RPUSH")
+            .arg(queue)
+            .arg(payload)
+            .ignore()
+            .cmd("HDEL")
+            .arg(self.origins_key.as_str())
+            .arg(payload)
+            .ignore()
+            .query(&mut *lock(&self.client))?)
+    }
+
+    /// Move a payload from the *processing* queue to the dead-letter queue of
+    /// its source queue, without decoding it.
+    fn dead_letter_payload(
+        &self,
+        source: &str,
+        payload: Vec<u8>,
+        reason: &str,
+    ) -> Result<(), Error> {
+        let envelope = envelope::Envelope::parse(&payload)
+            .ok()
+            .and_then(|(envelope, _)| envelope);
+        let entry = DeadLetter::new(
+            payload.clone(),
+            envelope.as_ref(),
+            reason,
+            &self.name,
+        );
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .cmd("LPUSH")
+            .arg(DEAD_LETTER_QUEUE_KEY.replace("{queue}", source))
+            .arg(entry.encode()?)
+            .ignore()
+            .cmd("LREM")
+            .arg(self.processing_queue_name.as_str())
+            .arg(1)
+            .arg(payload.as_slice())
+            .ignore()
+            .cmd("HDEL")
+            .arg(self.origins_key.as_str())
+            .arg(payload.as_slice())
+            .ignore();
+        self.clear_deadline(&mut pipe, envelope.as_ref(), &payload);
+        Ok(pipe.query(&mut *lock(&self.client))?)
+    }
+
+    /// Add the commands removing the deadline of a message to the pipeline.
+    pub(crate) fn clear_deadline(
+        &self,
+        pipe: &mut redis::Pipeline,
+        envelope: Option<&envelope::Envelope>,
+        payload: &[u8],
+    ) {
+        let member = envelope::deadline_member(envelope, payload);
+        pipe.cmd("ZREM")
+            .arg(self.deadlines_key.as_str())
+            .arg(member)
+            .ignore()
+            .cmd("HDEL")
+            .arg(self.inflight_key.as_str())
+            .arg(member)
+            .ignore();
+    }
+
+    /// Handle a payload that failed verification according to the signature
+    /// policy.
+    fn discard_unverified(
+        &self,
+        source: &str,
+        payload: Vec<u8>,
+        reason: &str,
+    ) -> Result<(), Error> {
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        match self.signature_policy {
+            SignaturePolicy::DeadLetter => {
+                return self.dead_letter_payload(source, payload, reason)
+            }
+            SignaturePolicy::Reject => {
+                return self.reject_payload(&payload, reason)
+            }
+            SignaturePolicy::Drop => {
+                pipe.cmd("HDEL")
+                    .arg(self.origins_key.as_str())
+                    .arg(payload.as_slice())
+                    .ignore();
+            }
         }
+        pipe.cmd("LREM")
+            .arg(self.processing_queue_name.as_str())
+            .arg(1)
+            .arg(payload.as_slice())
+            .ignore();
+        let envelope = envelope::Envelope::parse(&payload)
+            .ok()
+            .and_then(|(envelope, _)| envelope);
+        self.clear_deadline(&mut pipe, envelope.as_ref(), &payload);
+        Ok(pipe.query(&mut *lock(&self.client))?)
+    }
 
-        let v = match v {
-            v @ Value::Data(_) => v,
-            _ => {
-                return Some(Err("unknown result type for next message"));
+    /// Move a payload from the *processing* queue to the *unack* queue, with
+    /// the reason in the rejections hash, without decoding it.
+    fn reject_payload(&self, payload: &[u8], reason: &str) -> Result<(), Error> {
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .cmd("LPUSH")
+            .arg(self.unacked_queue_name.as_str())
+            .arg(payload)
+            .ignore()
+            .cmd("HSET")
+            .arg(self.rejections_key.as_str())
+            .arg(payload)
+            .arg(reason)
+            .ignore()
+            .cmd("LREM")
+            .arg(self.processing_queue_name.as_str())
+            .arg(1)
+            .arg(payload)
+            .ignore();
+        let envelope = envelope::Envelope::parse(payload)
+            .ok()
+            .and_then(|(envelope, _)| envelope);
+        self.clear_deadline(&mut pipe, envelope.as_ref(), payload);
+        Ok(pipe.query(&mut *lock(&self.client))?)
+    }
+
+    /// Get the lists to fetch the next message from, in order, each with the
+    /// index of the source queue it belongs to.
+    fn fetch_order(&self) -> Vec<(String, usize)> {
+        let n = self.source_queue_names.len();
+        let start = match self.source_order {
+            SourceOrder::Strict => 0,
+            SourceOrder::RoundRobin => {
+                self.next_source.fetch_add(1, Ordering::Relaxed) % n
             }
         };
+        (0..n)
+            .map(|i| (start + i) % n)
+            .flat_map(|i| {
+                self.priority_mode
+                    .queue_names(&self.source_queue_names[i])
+                    .into_iter()
+                    .map(move |q| (q, i))
+            })
+            .collect()
+    }
 
-        match T::decode_message(&v) {
-            Err(e) => Some(Err(e)),
-            Ok(message) => Some(Ok(message::MessageGuard::new(
-                message,
-                from_redis_value(&v).unwrap(),
-                &self.client,
-                self.processing_queue_name.clone(),
-                self.unacked_queue_name.clone(),
-            ))),
+    /// Process jobs with the given handler until the consumer is stopped.
+    ///
+    /// Jobs are acknowledged if the handler returns `Ok` and rejected
+    /// otherwise. Panics in the handler are caught and the job is rejected as
+    /// well, so that the consumer keeps running. In both cases the error or
+    /// the panic message is recorded alongside the rejected payload (see
+    /// `Consumer::rejection_reason()`).
+    ///
+    /// Jobs that cannot be decoded are rejected as well, with the decoding
+    /// error as the reason, while Redis errors interrupt the processing and
+    /// are returned.
+    pub fn run<T, E, F>(&self, mut handler: F) -> Result<(), Error>
+    where
+        T: message::MessageDecodable,
+        E: fmt::Display,
+        F: FnMut(&T) -> Result<(), E>,
+    {
+        while let Some(res) = self.next_message::<T>(true) {
+            let mut message = match res {
+                Ok(message) => message,
+                Err(e) if e.is_redis() => return Err(e),
+                Err(_) => continue,
+            };
+            match panic::catch_unwind(AssertUnwindSafe(|| handler(&message))) {
+                Ok(Ok(())) => message.ack()?,
+                Ok(Err(e)) => message.reject_with_reason(&e.to_string())?,
+                Err(p) => message.reject_with_reason(&panic_message(&*p))?,
+            };
         }
+        Ok(())
+    }
+}
+
+/// Extract the message from a panic payload.
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        format!("handler panicked: {}", s)
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        format!("handler panicked: {}", s)
+    } else {
+        "handler panicked".into()
     }
 }
@@ -0,0 +1,424 @@
+use crate::codec::MsgPackCodec;
+use crate::consumer::{now_millis, DEAD_LETTER_QUEUE_KEY};
+use crate::encryption::Keyring;
+use crate::envelope::{self, Envelope};
+use crate::error::Error;
+use crate::message::MessageDecodable;
+use crate::signing::Signer;
+use redis::{Commands, Script};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use uuid::Uuid;
+
+// Remove the entry from the dead-letter queue and push its payload to the
+// source queue, unless another client got there first.
+const REPLAY_SCRIPT: &str = r"
+if redis.call('LREM', KEYS[1], 1, ARGV[1]) == 1 then
+    redis.call('LPUSH', KEYS[2], ARGV[2])
+    return 1
+end
+return 0
+";
+
+/// Number of entries fetched at a time when looking for an entry by id.
+const PAGE_SIZE: usize = 100;
+
+/// A message moved to the dead-letter queue, along with the reason it failed.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    id: String,
+    #[serde(with = "serde_bytes")]
+    payload: Vec<u8>,
+    reason: String,
+    consumer: String,
+    dead_lettered_at: u64,
+}
+
+impl DeadLetter {
+    /// Create an entry for the given payload. The id of the envelope is reused
+    /// if there is one, otherwise a random id is generated.
+    pub(crate) fn new(
+        payload: Vec<u8>,
+        envelope: Option<&Envelope>,
+        reason: &str,
+        consumer: &str,
+    ) -> DeadLetter {
+        let id = match envelope {
+            Some(envelope) => envelope.id().to_string(),
+            None => Uuid::new_v4().to_string(),
+        };
+        DeadLetter {
+            id,
+            payload,
+            reason: reason.into(),
+            consumer: consumer.into(),
+            dead_lettered_at: now_millis(),
+        }
+    }
+
+    pub(crate) fn encode(&self) -> Result<Vec<u8>, Error> {
+        Ok(rmp_serde::encode::to_vec_named(self)?)
+    }
+
+    fn decode(entry: &[u8]) -> Result<DeadLetter, Error> {
+        Ok(rmp_serde::decode::from_slice(entry)?)
+    }
+
+    /// Get the id of the entry, which is the id of the message if it was
+    /// pushed with an envelope.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Get the raw payload of the message.
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// Get the reason why the message was dead-lettered.
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+
+    /// Get the name of the consumer that dead-lettered the message.
+    pub fn consumer(&self) -> &str {
+        &self.consumer
+    }
+
+    /// Get the time the message was dead-lettered, in milliseconds since the
+    /// Unix epoch.
+    pub fn dead_lettered_at(&self) -> u64 {
+        self.dead_lettered_at
+    }
+
+    /// Get the envelope of the message, if it was pushed with one.
+    pub fn envelope(&self) -> Result<Option<Envelope>, Error> {
+        Ok(Envelope::parse(&self.payload)?.0)
+    }
+
+    /// Decode the message, with the codec recorded in its envelope. Bare
+    /// payloads are decoded with Msgpack.
+    pub fn message<T: MessageDecodable>(&self) -> Result<T, Error> {
+        Ok(envelope::decode_payload(&self.payload, &MsgPackCodec, None)?.0)
+    }
+
+    /// Decrypt and decode the message.
+    pub fn decrypted_message<T: MessageDecodable>(
+        &self,
+        keyring: &Keyring,
+    ) -> Result<T, Error> {
+        Ok(
+            envelope::decode_payload(&self.payload, &MsgPackCodec, Some(keyring))?
+                .0,
+        )
+    }
+
+    /// Get the payload to push back to the source queue, with its delivery
+    /// attempts reset. Messages with an envelope are signed again if a signer
+    /// is given, provided that their current signature verifies with it or
+    /// that `unverified` is set.
+    fn replay_payload(
+        &self,
+        signer: Option<&Signer>,
+        unverified: bool,
+    ) -> Result<Vec<u8>, Error> {
+        match Envelope::parse(&self.payload)? {
+            (Some(envelope), body) => {
+                let payload = envelope.replayed().wrap(body)?;
+                match signer {
+                    Some(signer) => {
+                        if !unverified {
+                            signer.verify(&self.payload)?;
+                        }
+                        Ok(signer.sign(&payload))
+                    }
+                    None => Ok(payload),
+                }
+            }
+            (None, _) => Ok(self.payload.clone()),
+        }
+    }
+}
+
+/// Inspect, replay and purge the dead-letter queue of a source queue.
+///
+/// Entries are listed from the most recently dead-lettered one. Replayed
+/// messages are pushed back to the source queue with their delivery attempts
+/// reset.
+pub struct DeadLetterQueue {
+    source_queue_name: String,
+    dead_letter_queue_name: String,
+    signer: Option<Signer>,
+    replay_script: Script,
+    client: RefCell<redis::Connection>,
+}
+
+impl DeadLetterQueue {
+    pub fn new(
+        source_queue_name: String,
+        client: redis::Connection,
+    ) -> DeadLetterQueue {
+        let dead_letter_queue_name =
+            DEAD_LETTER_QUEUE_KEY.replace("{queue}", source_queue_name.as_str());
+        DeadLetterQueue {
+            source_queue_name,
+            dead_letter_queue_name,
+            signer: None,
+            replay_script: Script::new(REPLAY_SCRIPT),
+            client: RefCell::new(client),
+        }
+    }
+
+    /// Get the source queue name.
+    pub fn source_queue(&self) -> &str {
+        &self.source_queue_name
+    }
+
+    /// Get the dead-letter queue name.
+    pub fn dead_letter_queue(&self) -> &str {
+        &self.dead_letter_queue_name
+    }
+
+    /// Set the signer used to sign the replayed messages again, for consumers
+    /// that verify signatures.
+    ///
+    /// Only the messages whose signature verifies with it are signed again:
+    /// the unsigned or forged ones, e.g. dead-lettered by a consumer because of
+    /// their signature, are only replayed with `replay_unverified()`.
+    pub fn set_signer(&mut self, signer: Signer) {
+        self.signer = Some(signer);
+    }
+
+    /// Get the signer.
+    pub fn signer(&self) -> Option<&Signer> {
+        self.signer.as_ref()
+    }
+
+    /// Get the number of entries in the dead-letter queue.
+    pub fn size(&self) -> Result<u64, Error> {
+        Ok(self
+            .client
+            .borrow_mut()
+            .llen(self.dead_letter_queue_name.as_str())?)
+    }
+
+    /// List at most `count` entries, skipping the first `offset` ones.
+    ///
+    /// Entries that cannot be decoded are skipped, so fewer than `count` ones
+    /// may be returned even if there are more.
+    pub fn list(
+        &self,
+        offset: usize,
+        count: usize,
+    ) -> Result<Vec<DeadLetter>, Error> {
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        let end = offset.saturating_add(count - 1).min(isize::MAX as usize);
+        let entries: Vec<Vec<u8>> = self.client.borrow_mut().lrange(
+            self.dead_letter_queue_name.as_str(),
+            offset as isize,
+            end as isize,
+        )?;
+        Ok(decode_entries(entries).map(|(_, entry)| entry).collect())
+    }
+
+    /// Get the entry with the given id.
+    pub fn get(&self, id: &str) -> Result<Option<DeadLetter>, Error> {
+        Ok(self.find(id)?.map(|(_, entry)| entry))
+    }
+
+    /// Push the message with the given id back to the source queue.
+    ///
+    /// Returns `false` if there is no such entry, e.g. because it was already
+    /// replayed or purged. With a signer, fails with `Error::Signature` if the
+    /// signature of the message does not verify.
+    pub fn replay(&self, id: &str) -> Result<bool, Error> {
+        match self.find(id)? {
+            Some((raw, entry)) => self.replay_entry(&raw, &entry, false),
+            None => Ok(false),
+        }
+    }
+
+    /// Push the message with the given id back to the source queue, signing it
+    /// with the signer even if its signature does not verify.
+    ///
+    /// This makes a message that was unsigned or forged trusted by the
+    /// consumers: it must only be called for messages known to be genuine.
+    pub fn replay_unverified(&self, id: &str) -> Result<bool, Error> {
+        match self.find(id)? {
+            Some((raw, entry)) => self.replay_entry(&raw, &entry, true),
+            None => Ok(false),
+        }
+    }
+
+    /// Push all the messages back to the source queue, from the oldest one.
+    ///
+    /// With a signer, the messages whose signature does not verify are left
+    /// in the dead-letter queue, as are the entries that cannot be decoded.
+    /// Returns the number of replayed messages.
+    pub fn replay_all(&self) -> Result<u64, Error> {
+        let mut total = 0;
+        // Pages are read from the tail, which new entries do not shift: only
+        // the entries left in the queue are skipped to get to the next page.
+        let mut kept = 0;
+        loop {
+            let end = -1 - kept as isize;
+            let entries: Vec<Vec<u8>> = self.client.borrow_mut().lrange(
+                self.dead_letter_queue_name.as_str(),
+                end - PAGE_SIZE as isize + 1,
+                end,
+            )?;
+            let last = entries.len() < PAGE_SIZE;
+            for raw in entries.into_iter().rev() {
+                let entry = match DeadLetter::decode(&raw) {
+                    Ok(entry) => entry,
+                    Err(_) => {
+                        kept += 1;
+                        continue;
+                    }
+                };
+                match self.replay_entry(&raw, &entry, false) {
+                    Ok(true) => total += 1,
+                    Ok(false) => (),
+                    Err(e) if e.is_signature() => kept += 1,
+                    Err(e) => return Err(e),
+                }
+            }
+            if last {
+                return Ok(total);
+            }
+        }
+    }
+
+    /// Delete the entry with the given id.
+    ///
+    /// Returns `false` if there is no such entry.
+    pub fn purge(&self, id: &str) -> Result<bool, Error> {
+        let raw = match self.find(id)? {
+            Some((raw, _)) => raw,
+            None => return Ok(false),
+        };
+        let n: u64 = self.client.borrow_mut().lrem(
+            self.dead_letter_queue_name.as_str(),
+            1,
+            raw,
+        )?;
+        Ok(n == 1)
+    }
+
+    /// Delete all the entries.
+    ///
+    /// Returns the number of deleted entries.
+    pub fn purge_all(&self) -> Result<u64, Error> {
+        let (n,): (u64,) = redis::pipe()
+            .atomic()
+            .cmd("LLEN")
+            .arg(self.dead_letter_queue_name.as_str())
+            .cmd("DEL")
+            .arg(self.dead_letter_queue_name.as_str())
+            .ignore()
+            .query(&mut *self.client.borrow_mut())?;
+        Ok(n)
+    }
+
+    /// Look for the entry with the given id, a page at a time from the most
+    /// recent one.
+    fn find(&self, id: &str) -> Result<Option<(Vec<u8>, DeadLetter)>, Error> {
+        let mut start = 0;
+        loop {
+            let entries: Vec<Vec<u8>> = self.client.borrow_mut().lrange(
+                self.dead_letter_queue_name.as_str(),
+                start as isize,
+                (start + PAGE_SIZE - 1) as isize,
+            )?;
+            let last = entries.len() < PAGE_SIZE;
+            let found = decode_entries(entries).find(|(_, entry)| entry.id == id);
+            if found.is_some() || last {
+                return Ok(found);
+            }
+            start += PAGE_SIZE;
+        }
+    }
+
+    fn replay_entry(
+        &self,
+        raw: &[u8],
+        entry: &DeadLetter,
+        unverified: bool,
+    ) -> Result<bool, Error> {
+        let replayed: u64 = self
+            .replay_script
+            .key(self.dead_letter_queue_name.as_str())
+            .key(self.source_queue_name.as_str())
+            .arg(raw)
+            .arg(entry.replay_payload(self.signer.as_ref(), unverified)?)
+            .invoke(&mut *self.client.borrow_mut())?;
+        Ok(replayed == 1)
+    }
+}
+
+/// Decode raw entries, skipping the ones that cannot be decoded so that a
+/// corrupt entry does not prevent looking up the others.
+fn decode_entries(
+    entries: Vec<Vec<u8>>,
+) -> impl Iterator<Item = (Vec<u8>, DeadLetter)> {
+    entries.into_iter().filter_map(|raw| {
+        let entry = DeadLetter::decode(&raw).ok()?;
+        Some((raw, entry))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn encodes_and_decodes() {
+        let body = rmp_serde::encode::to_vec(&42u64).unwrap();
+        let envelope = Envelope::new(HashMap::new());
+        let payload = envelope.wrap(&body).unwrap();
+        let entry = DeadLetter::new(payload, Some(&envelope), "boom", "c1");
+
+        let decoded = DeadLetter::decode(&entry.encode().unwrap()).unwrap();
+        assert_eq!(entry, decoded);
+        assert_eq!(envelope.id(), decoded.id());
+        assert_eq!(42u64, decoded.message::<u64>().unwrap());
+    }
+
+    #[test]
+    fn replay_resets_attempts() {
+        let body = rmp_serde::encode::to_vec(&42u64).unwrap();
+        let envelope = Envelope::new(HashMap::new()).retried().retried();
+        let payload = envelope.wrap(&body).unwrap();
+        let entry = DeadLetter::new(payload, Some(&envelope), "boom", "c1");
+
+        let replayed = entry.replay_payload(None, false).unwrap();
+        let (parsed, rest) = Envelope::parse(&replayed).unwrap();
+        let parsed = parsed.unwrap();
+        assert_eq!(0, parsed.attempts());
+        assert_eq!(envelope.id(), parsed.id());
+        assert_eq!(body.as_slice(), rest);
+    }
+
+    #[test]
+    fn replay_signs_only_verified_payloads() {
+        let body = rmp_serde::encode::to_vec(&42u64).unwrap();
+        let envelope = Envelope::new(HashMap::new());
+        let payload = envelope.wrap(&body).unwrap();
+        let signer = Signer::new("k1", b"secret");
+
+        let forged = Signer::new("k1", b"forged").sign(&payload);
+        let entry = DeadLetter::new(forged, Some(&envelope), "boom", "c1");
+        let err = entry.replay_payload(Some(&signer), false).unwrap_err();
+        assert!(err.is_signature());
+        let replayed = entry.replay_payload(Some(&signer), true).unwrap();
+        assert!(signer.verify(&replayed).is_ok());
+
+        let signed = signer.sign(&payload);
+        let entry = DeadLetter::new(signed, Some(&envelope), "boom", "c1");
+        let replayed = entry.replay_payload(Some(&signer), false).unwrap();
+        assert!(signer.verify(&replayed).is_ok());
+    }
+}
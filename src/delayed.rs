@@ -0,0 +1,43 @@
+use redis::{RedisResult, Script};
+
+// Move the due messages from the delayed set to the source queue, then return
+// the number of moved messages and the score of the next one, or -1.
+pub(crate) const PROMOTE_SCRIPT: &str = r"
+local due = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1], 'LIMIT', 0, ARGV[2])
+for _, payload in ipairs(due) do
+    redis.call('LPUSH', KEYS[2], payload)
+    redis.call('ZREM', KEYS[1], payload)
+end
+local next = redis.call('ZRANGE', KEYS[1], 0, 0, 'WITHSCORES')
+if next[2] then
+    return {#due, tonumber(next[2])}
+end
+return {#due, -1}
+";
+
+/// Atomically move up to `limit` messages whose due time (in milliseconds
+/// since the Unix epoch) is not after `now` from the delayed set to the source
+/// queue.
+///
+/// The script is `PROMOTE_SCRIPT`, which callers keep so that its hash is not
+/// computed again on every poll.
+///
+/// Returns the number of moved messages and the due time of the next delayed
+/// message, if any.
+pub(crate) fn promote(
+    con: &mut dyn redis::ConnectionLike,
+    script: &Script,
+    delayed_key: &str,
+    source_queue: &str,
+    now: u64,
+    limit: u64,
+) -> RedisResult<(u64, Option<u64>)> {
+    let (moved, next): (u64, i64) = script
+        .key(delayed_key)
+        .key(source_queue)
+        .arg(now)
+        .arg(limit)
+        .invoke(con)?;
+    let next = if next < 0 { None } else { Some(next as u64) };
+    Ok((moved, next))
+}
@@ -0,0 +1,257 @@
+use crate::error::Error;
+use rand::Rng;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Size in bytes of the random nonce prepended to each encrypted message.
+const NONCE_SIZE: usize = 12;
+
+/// The authenticated cipher used to encrypt messages.
+///
+/// Each cipher is enabled by the cargo feature with the same name: `aes-gcm`
+/// and `chacha20poly1305`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Cipher {
+    #[cfg(feature = "aes-gcm")]
+    Aes256Gcm,
+    #[cfg(feature = "chacha20poly1305")]
+    ChaCha20Poly1305,
+}
+
+// Unused arguments are allowed for the same reason as in `Compression`.
+#[cfg_attr(
+    not(any(feature = "aes-gcm", feature = "chacha20poly1305")),
+    allow(unused_variables)
+)]
+impl Cipher {
+    fn encrypt(
+        self,
+        key: &[u8; 32],
+        nonce: &[u8; NONCE_SIZE],
+        aad: &[u8],
+        plaintext: &[u8],
+    ) -> Result<Vec<u8>, ()> {
+        match self {
+            #[cfg(feature = "aes-gcm")]
+            Cipher::Aes256Gcm => {
+                use aes_gcm::aead::{Aead, NewAead, Payload};
+
+                aes_gcm::Aes256Gcm::new(&aes_gcm::Key::from(*key))
+                    .encrypt(
+                        &aes_gcm::Nonce::from(*nonce),
+                        Payload {
+                            msg: plaintext,
+                            aad,
+                        },
+                    )
+                    .map_err(|_| ())
+            }
+            #[cfg(feature = "chacha20poly1305")]
+            Cipher::ChaCha20Poly1305 => {
+                use chacha20poly1305::aead::{Aead, NewAead, Payload};
+
+                chacha20poly1305::ChaCha20Poly1305::new(
+                    &chacha20poly1305::Key::from(*key),
+                )
+                .encrypt(
+                    &chacha20poly1305::Nonce::from(*nonce),
+                    Payload {
+                        msg: plaintext,
+                        aad,
+                    },
+                )
+                .map_err(|_| ())
+            }
+        }
+    }
+
+    fn decrypt(
+        self,
+        key: &[u8; 32],
+        nonce: &[u8; NONCE_SIZE],
+        aad: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, ()> {
+        match self {
+            #[cfg(feature = "aes-gcm")]
+            Cipher::Aes256Gcm => {
+                use aes_gcm::aead::{Aead, NewAead, Payload};
+
+                aes_gcm::Aes256Gcm::new(&aes_gcm::Key::from(*key))
+                    .decrypt(
+                        &aes_gcm::Nonce::from(*nonce),
+                        Payload {
+                            msg: ciphertext,
+                            aad,
+                        },
+                    )
+                    .map_err(|_| ())
+            }
+            #[cfg(feature = "chacha20poly1305")]
+            Cipher::ChaCha20Poly1305 => {
+                use chacha20poly1305::aead::{Aead, NewAead, Payload};
+
+                chacha20poly1305::ChaCha20Poly1305::new(
+                    &chacha20poly1305::Key::from(*key),
+                )
+                .decrypt(
+                    &chacha20poly1305::Nonce::from(*nonce),
+                    Payload {
+                        msg: ciphertext,
+                        aad,
+                    },
+                )
+                .map_err(|_| ())
+            }
+        }
+    }
+}
+
+/// The keys used to encrypt and decrypt messages, by id.
+///
+/// Producers encrypt with the primary key and record its id in the envelope,
+/// so that consumers know which key decrypts the message. Keys are rotated by
+/// adding a new primary key with `Keyring::rotate()`: the previous keys are
+/// still used to decrypt older messages, until they are removed.
+///
+/// Only the encoded message is encrypted: the envelope, and so the headers,
+/// are stored in plaintext.
+#[derive(Clone)]
+pub struct Keyring {
+    keys: HashMap<String, (Cipher, [u8; 32])>,
+    primary: String,
+}
+
+impl Keyring {
+    /// Create a keyring with the given primary key.
+    pub fn new(id: &str, cipher: Cipher, key: [u8; 32]) -> Keyring {
+        let mut keys = HashMap::new();
+        keys.insert(id.to_string(), (cipher, key));
+        Keyring {
+            keys,
+            primary: id.into(),
+        }
+    }
+
+    /// Add a key used only to decrypt messages.
+    pub fn add_key(&mut self, id: &str, cipher: Cipher, key: [u8; 32]) {
+        self.keys.insert(id.into(), (cipher, key));
+    }
+
+    /// Add a key and make it the primary key. The previous primary key is
+    /// kept to decrypt older messages.
+    pub fn rotate(&mut self, id: &str, cipher: Cipher, key: [u8; 32]) {
+        self.add_key(id, cipher, key);
+        self.primary = id.into();
+    }
+
+    /// Remove a key that is not the primary key.
+    ///
+    /// Returns `false` if there is no such key or if it is the primary key.
+    pub fn remove_key(&mut self, id: &str) -> bool {
+        id != self.primary && self.keys.remove(id).is_some()
+    }
+
+    /// Get the id of the primary key.
+    pub fn primary_key_id(&self) -> &str {
+        &self.primary
+    }
+
+    /// Get the ids of all the keys.
+    pub fn key_ids(&self) -> Vec<&str> {
+        self.keys.keys().map(String::as_str).collect()
+    }
+
+    /// Encrypt a message with the primary key, authenticating the given
+    /// additional data.
+    ///
+    /// Returns the id of the key and the random nonce followed by the
+    /// ciphertext.
+    pub(crate) fn encrypt(
+        &self,
+        aad: &[u8],
+        plaintext: &[u8],
+    ) -> Result<(&str, Vec<u8>), Error> {
+        let (cipher, key) = &self.keys[&self.primary];
+        let mut nonce = [0u8; NONCE_SIZE];
+        rand::thread_rng().fill(&mut nonce);
+        let ciphertext = cipher
+            .encrypt(key, &nonce, aad, plaintext)
+            .map_err(|_| Error::Encode("failed to encrypt message".into()))?;
+        let mut encrypted = nonce.to_vec();
+        encrypted.extend(ciphertext);
+        Ok((&self.primary, encrypted))
+    }
+
+    /// Decrypt a message encrypted with the given key.
+    pub(crate) fn decrypt(
+        &self,
+        id: &str,
+        aad: &[u8],
+        encrypted: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let (cipher, key) = self
+            .keys
+            .get(id)
+            .ok_or_else(|| Error::Decrypt(format!("unknown key {}", id).into()))?;
+        if encrypted.len() < NONCE_SIZE {
+            return Err(Error::Decrypt("message is too short".into()));
+        }
+        let (prefix, ciphertext) = encrypted.split_at(NONCE_SIZE);
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce.copy_from_slice(prefix);
+        cipher.decrypt(key, &nonce, aad, ciphertext).map_err(|_| {
+            Error::Decrypt(
+                format!("message cannot be authenticated with key {}", id).into(),
+            )
+        })
+    }
+}
+
+impl fmt::Debug for Keyring {
+    // Keys are never printed.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Keyring")
+            .field("keys", &self.key_ids())
+            .field("primary", &self.primary)
+            .finish()
+    }
+}
+
+#[cfg(all(test, feature = "aes-gcm"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypts_and_decrypts() {
+        let keyring = Keyring::new("k1", Cipher::Aes256Gcm, [1; 32]);
+        let (id, encrypted) = keyring.encrypt(b"id", b"secret").unwrap();
+        assert_eq!("k1", id);
+        assert!(!encrypted.windows(6).any(|w| w == b"secret"));
+        assert_eq!(
+            b"secret".to_vec(),
+            keyring.decrypt("k1", b"id", &encrypted).unwrap()
+        );
+
+        let err = keyring.decrypt("k1", b"other", &encrypted).unwrap_err();
+        assert!(err.is_decrypt());
+        let err = keyring.decrypt("k2", b"id", &encrypted).unwrap_err();
+        assert!(err.is_decrypt());
+    }
+
+    #[test]
+    fn rotated_keys_still_decrypt() {
+        let mut keyring = Keyring::new("k1", Cipher::Aes256Gcm, [1; 32]);
+        let (_, old) = keyring.encrypt(b"id", b"old").unwrap();
+        keyring.rotate("k2", Cipher::Aes256Gcm, [2; 32]);
+        let (id, new) = keyring.encrypt(b"id", b"new").unwrap();
+
+        assert_eq!("k2", id);
+        assert_eq!(b"old".to_vec(), keyring.decrypt("k1", b"id", &old).unwrap());
+        assert_eq!(b"new".to_vec(), keyring.decrypt("k2", b"id", &new).unwrap());
+        assert!(!keyring.remove_key("k2"));
+        assert!(keyring.remove_key("k1"));
+        assert!(keyring.decrypt("k1", b"id", &old).is_err());
+        assert!(format!("{:?}", keyring).contains("k2"));
+    }
+}
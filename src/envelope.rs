@@ -0,0 +1,555 @@
+use crate::codec::{self, Codec, MsgPackCodec};
+use crate::compression::{self, Compression};
+use crate::consumer::now_millis;
+use crate::encryption::Keyring;
+use crate::error::Error;
+use crate::message::{MessageDecodable, MessageEncodable};
+use crate::signing::Signer;
+use redis::Value;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Marks a payload wrapped in an envelope. 0xc1 is never used by Msgpack, so
+/// it cannot be the first byte of a bare Msgpack payload. Bare payloads in
+/// other formats may start with it: they are told apart by the version and the
+/// envelope that follow, which they are unlikely to have.
+const MAGIC: u8 = 0xc1;
+/// Version of the envelopes of Msgpack-encoded messages.
+const VERSION: u8 = 1;
+/// Version of the envelopes with a codec tag.
+const VERSION_TAGGED: u8 = 2;
+/// Version of the envelopes with a codec tag and a compression tag.
+const VERSION_COMPRESSED: u8 = 3;
+/// Version of the signed payloads, which wrap another payload.
+const VERSION_SIGNED: u8 = 4;
+/// Size in bytes of the HMAC-SHA256 signatures.
+const SIGNATURE_SIZE: usize = 32;
+
+/// Metadata stored alongside the encoded message.
+///
+/// A payload wrapped in an envelope is made of a two bytes prefix, the
+/// Msgpack-encoded envelope and the encoded message. Messages encoded with a
+/// codec other than Msgpack have a third prefix byte, the tag of the codec, and
+/// compressed messages a fourth one, the tag of the compression algorithm.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Envelope {
+    id: String,
+    created_at: u64,
+    attempts: u32,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_id: Option<String>,
+    #[serde(skip)]
+    codec_tag: u8,
+    #[serde(skip)]
+    compression: Option<Compression>,
+}
+
+impl Envelope {
+    /// Create an envelope for a new message, with a random id.
+    pub fn new(headers: HashMap<String, String>) -> Envelope {
+        Envelope {
+            id: Uuid::new_v4().to_string(),
+            created_at: now_millis(),
+            attempts: 0,
+            headers,
+            key_id: None,
+            codec_tag: MsgPackCodec.tag(),
+            compression: None,
+        }
+    }
+
+    /// Set the codec the message is encoded with, recording its tag. Defaults
+    /// to `MsgPackCodec`.
+    pub fn set_codec<C: Codec>(&mut self, codec: &C) {
+        self.codec_tag = codec.tag();
+    }
+
+    /// Get the tag of the codec the message is encoded with.
+    pub fn codec_tag(&self) -> u8 {
+        self.codec_tag
+    }
+
+    /// Set the algorithm the encoded message is compressed with.
+    pub fn set_compression(&mut self, compression: Option<Compression>) {
+        self.compression = compression;
+    }
+
+    /// Get the algorithm the encoded message is compressed with, if any.
+    pub fn compression(&self) -> Option<Compression> {
+        self.compression
+    }
+
+    /// Get the unique id of the message.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Get the time the message was first pushed, in milliseconds since the
+    /// Unix epoch.
+    pub fn created_at(&self) -> u64 {
+        self.created_at
+    }
+
+    /// Get the number of failed delivery attempts of the message.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Get the headers of the message.
+    pub fn headers(&self) -> &HashMap<String, String> {
+        &self.headers
+    }
+
+    /// Get the id of the key the encoded message is encrypted with, if any.
+    pub fn key_id(&self) -> Option<&str> {
+        self.key_id.as_deref()
+    }
+
+    /// Get the value of a header.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+
+    /// Get a copy of the envelope with one more failed attempt.
+    pub(crate) fn retried(&self) -> Envelope {
+        Envelope {
+            attempts: self.attempts + 1,
+            ..self.clone()
+        }
+    }
+
+    /// Get a copy of the envelope with no failed attempts, for replaying a
+    /// dead-lettered message.
+    pub(crate) fn replayed(&self) -> Envelope {
+        Envelope {
+            attempts: 0,
+            ..self.clone()
+        }
+    }
+
+    /// Wrap the encoded message in the envelope.
+    pub fn wrap(&self, body: &[u8]) -> Result<Vec<u8>, Error> {
+        // Msgpack envelopes keep the original format, so that they can still
+        // be read by older consumers.
+        let mut payload = if let Some(compression) = self.compression {
+            vec![MAGIC, VERSION_COMPRESSED, self.codec_tag, compression.tag()]
+        } else if self.codec_tag != MsgPackCodec.tag() {
+            vec![MAGIC, VERSION_TAGGED, self.codec_tag]
+        } else {
+            vec![MAGIC, VERSION]
+        };
+        payload.extend(rmp_serde::encode::to_vec_named(self)?);
+        payload.extend_from_slice(body);
+        Ok(payload)
+    }
+
+    /// Split a payload into its envelope and the encoded message.
+    ///
+    /// Bare payloads, pushed without an envelope, are returned unchanged. The
+    /// signature of signed payloads is skipped, without being verified.
+    ///
+    /// A payload starting with the magic byte is only taken for an envelope if
+    /// its version is known and the envelope decodes, and for a signed payload
+    /// if the signed payload is an envelope. Otherwise it is returned
+    /// unchanged, as a bare payload in a format other than Msgpack.
+    pub fn parse(payload: &[u8]) -> Result<(Option<Envelope>, &[u8]), Error> {
+        if let Ok(Some((_, _, signed))) = split_signature(payload) {
+            return match Envelope::parse(signed)? {
+                (None, _) => Ok((None, payload)),
+                parsed => Ok(parsed),
+            };
+        }
+        let (tags_len, rest) = match payload {
+            [MAGIC, VERSION, rest @ ..] => (0, rest),
+            [MAGIC, VERSION_TAGGED, rest @ ..] => (1, rest),
+            [MAGIC, VERSION_COMPRESSED, rest @ ..] => (2, rest),
+            _ => return Ok((None, payload)),
+        };
+        if rest.len() < tags_len {
+            return Ok((None, payload));
+        }
+        let (tags, mut body) = rest.split_at(tags_len);
+        let mut envelope: Envelope = match rmp_serde::decode::from_read(&mut body)
+        {
+            Ok(envelope) => envelope,
+            Err(_) => return Ok((None, payload)),
+        };
+        if let Some(&tag) = tags.first() {
+            envelope.codec_tag = tag;
+        }
+        if let Some(&tag) = tags.get(1) {
+            envelope.compression =
+                Some(Compression::from_tag(tag).ok_or_else(|| {
+                    Error::Decode(
+                        format!("unknown compression tag {}", tag).into(),
+                    )
+                })?);
+        }
+        Ok((Some(envelope), body))
+    }
+}
+
+/// Get the member of a message in the deadlines of its consumer: the id of its
+/// envelope, or the payload itself if it has none.
+pub(crate) fn deadline_member<'a>(
+    envelope: Option<&'a Envelope>,
+    payload: &'a [u8],
+) -> &'a [u8] {
+    envelope.map_or(payload, |e| e.id.as_bytes())
+}
+
+/// Wrap a payload with its signature and the id of the key.
+///
+/// A signed payload is made of a two bytes prefix, the length of the id, the
+/// id, the 32 bytes signature and the signed payload.
+pub(crate) fn join_signature(
+    key_id: &str,
+    signature: &[u8],
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut signed = vec![MAGIC, VERSION_SIGNED, key_id.len() as u8];
+    signed.extend_from_slice(key_id.as_bytes());
+    signed.extend_from_slice(signature);
+    signed.extend_from_slice(payload);
+    signed
+}
+
+/// The id of the key, the signature and the signed payload.
+pub(crate) type Signed<'a> = (&'a str, &'a [u8], &'a [u8]);
+
+/// Split a signed payload into the id of the key, the signature and the signed
+/// payload. Returns `None` if the payload is not signed.
+pub(crate) fn split_signature(
+    payload: &[u8],
+) -> Result<Option<Signed<'_>>, Error> {
+    let rest = match payload {
+        [MAGIC, VERSION_SIGNED, rest @ ..] => rest,
+        _ => return Ok(None),
+    };
+    let malformed = || Error::Decode("malformed signed payload".into());
+    let (len, rest) = rest.split_first().ok_or_else(malformed)?;
+    let len = *len as usize;
+    if rest.len() < len + SIGNATURE_SIZE {
+        return Err(malformed());
+    }
+    let (key_id, rest) = rest.split_at(len);
+    let key_id = std::str::from_utf8(key_id).map_err(|_| malformed())?;
+    let (signature, signed) = rest.split_at(SIGNATURE_SIZE);
+    Ok(Some((key_id, signature, signed)))
+}
+
+/// How producers turn messages into payloads.
+///
+/// Messages are encoded with the codec, then compressed if they are at least
+/// as big as the compression threshold, then encrypted with the primary key of
+/// the keyring. The payload is finally signed with the primary key of the
+/// signer. The codec and compression algorithm are recorded in the envelope,
+/// so that consumers need no configuration to decode the messages, except for
+/// the keys.
+#[derive(Clone)]
+pub struct EncodeOptions<C: Codec = MsgPackCodec> {
+    codec: C,
+    compression: Option<Compression>,
+    compression_threshold: usize,
+    keyring: Option<Keyring>,
+    signer: Option<Signer>,
+}
+
+impl EncodeOptions {
+    pub fn new() -> EncodeOptions {
+        EncodeOptions {
+            codec: MsgPackCodec,
+            compression: None,
+            compression_threshold: compression::DEFAULT_THRESHOLD,
+            keyring: None,
+            signer: None,
+        }
+    }
+}
+
+impl Default for EncodeOptions {
+    fn default() -> EncodeOptions {
+        EncodeOptions::new()
+    }
+}
+
+impl<C: Codec> EncodeOptions<C> {
+    /// Replace the codec. Defaults to `MsgPackCodec`.
+    pub fn with_codec<D: Codec>(self, codec: D) -> EncodeOptions<D> {
+        EncodeOptions {
+            codec,
+            compression: self.compression,
+            compression_threshold: self.compression_threshold,
+            keyring: self.keyring,
+            signer: self.signer,
+        }
+    }
+
+    /// Get the codec.
+    pub fn codec(&self) -> &C {
+        &self.codec
+    }
+
+    /// Set the compression algorithm. Defaults to `None`.
+    pub fn set_compression(&mut self, compression: Option<Compression>) {
+        self.compression = compression;
+    }
+
+    /// Get the compression algorithm.
+    pub fn compression(&self) -> Option<Compression> {
+        self.compression
+    }
+
+    /// Set the size in bytes from which encoded messages are compressed.
+    /// Defaults to 1024.
+    pub fn set_compression_threshold(&mut self, threshold: usize) {
+        self.compression_threshold = threshold;
+    }
+
+    /// Get the compression threshold.
+    pub fn compression_threshold(&self) -> usize {
+        self.compression_threshold
+    }
+
+    /// Set the keyring messages are encrypted with.
+    pub fn set_keyring(&mut self, keyring: Keyring) {
+        self.keyring = Some(keyring);
+    }
+
+    /// Get the keyring.
+    pub fn keyring(&self) -> Option<&Keyring> {
+        self.keyring.as_ref()
+    }
+
+    /// Set the signer payloads are signed with.
+    pub fn set_signer(&mut self, signer: Signer) {
+        self.signer = Some(signer);
+    }
+
+    /// Get the signer.
+    pub fn signer(&self) -> Option<&Signer> {
+        self.signer.as_ref()
+    }
+}
+
+/// Encode a message with the given options and wrap it in a new envelope.
+pub(crate) fn encode_payload<T: MessageEncodable, C: Codec>(
+    message: &T,
+    headers: HashMap<String, String>,
+    options: &EncodeOptions<C>,
+) -> Result<Vec<u8>, Error> {
+    let mut envelope = Envelope::new(headers);
+    envelope.set_codec(&options.codec);
+    let mut body = message.encode_message_with(&options.codec)?;
+    if let Some(compression) = options.compression {
+        if body.len() >= options.compression_threshold {
+            envelope.set_compression(Some(compression));
+            body = compression.compress(&body)?;
+        }
+    }
+    if let Some(ref keyring) = options.keyring {
+        // The ciphertext is bound to the message id, so that it cannot be
+        // moved to another envelope.
+        let (key_id, encrypted) =
+            keyring.encrypt(envelope.id.as_bytes(), &body)?;
+        envelope.key_id = Some(key_id.into());
+        body = encrypted;
+    }
+    let payload = envelope.wrap(&body)?;
+    Ok(match options.signer {
+        Some(ref signer) => signer.sign(&payload),
+        None => payload,
+    })
+}
+
+/// Decode a message fetched from Redis, unwrapping its envelope if present.
+///
+/// Enveloped messages are decrypted, decompressed and decoded as recorded in
+/// the envelope, with the given codec if it has the recorded tag. Bare payloads
+/// are decoded with the given codec.
+pub(crate) fn decode_payload<T: MessageDecodable, C: Codec>(
+    payload: &[u8],
+    codec: &C,
+    keyring: Option<&Keyring>,
+) -> Result<(T, Option<Envelope>), Error> {
+    let (envelope, body) = Envelope::parse(payload)?;
+    let envelope = match envelope {
+        Some(envelope) => envelope,
+        None => {
+            let message =
+                T::decode_message_with(&Value::Data(body.to_vec()), codec)?;
+            return Ok((message, None));
+        }
+    };
+    let body = match (envelope.key_id(), keyring) {
+        (Some(key_id), Some(keyring)) => {
+            keyring.decrypt(key_id, envelope.id.as_bytes(), body)?
+        }
+        (Some(key_id), None) => {
+            return Err(Error::Decrypt(
+                format!("no keyring to decrypt with key {}", key_id).into(),
+            ))
+        }
+        (None, _) => body.to_vec(),
+    };
+    let body = match envelope.compression() {
+        Some(compression) => compression.decompress(&body)?,
+        None => body,
+    };
+    let message =
+        codec::decode_tagged(&Value::Data(body), envelope.codec_tag(), codec)?;
+    Ok((message, Some(envelope)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wraps_and_parses() {
+        let mut headers = HashMap::new();
+        headers.insert("trace".to_string(), "abc".to_string());
+        let envelope = Envelope::new(headers);
+        let body = rmp_serde::encode::to_vec(&42u64).unwrap();
+
+        let payload = envelope.wrap(&body).unwrap();
+        let (parsed, rest) = Envelope::parse(&payload).unwrap();
+
+        assert_eq!(Some(envelope), parsed);
+        assert_eq!(body.as_slice(), rest);
+        assert_eq!(Some("abc"), parsed.unwrap().header("trace"));
+    }
+
+    #[test]
+    fn bare_payloads_are_unchanged() {
+        let body = rmp_serde::encode::to_vec(&42u64).unwrap();
+        let (envelope, rest) = Envelope::parse(&body).unwrap();
+
+        assert_eq!(None, envelope);
+        assert_eq!(body.as_slice(), rest);
+    }
+
+    #[test]
+    fn msgpack_envelopes_are_untagged() {
+        let payload = Envelope::new(HashMap::new()).wrap(&[]).unwrap();
+        assert_eq!(&[MAGIC, VERSION], &payload[..2]);
+    }
+
+    #[test]
+    fn unknown_codecs_are_poison() {
+        let mut payload = Envelope::new(HashMap::new()).wrap(&[1]).unwrap();
+        payload.splice(..2, vec![MAGIC, VERSION_TAGGED, 255]);
+        let (envelope, _) = Envelope::parse(&payload).unwrap();
+        assert_eq!(255, envelope.unwrap().codec_tag());
+        let err =
+            decode_payload::<u64, _>(&payload, &MsgPackCodec, None).unwrap_err();
+        assert!(err.is_poison());
+    }
+
+    #[test]
+    fn unknown_compressions_are_poison() {
+        let mut payload = Envelope::new(HashMap::new()).wrap(&[1]).unwrap();
+        payload.splice(..2, vec![MAGIC, VERSION_COMPRESSED, 0, 255]);
+        let err = Envelope::parse(&payload).unwrap_err();
+        assert!(err.is_poison());
+    }
+
+    #[test]
+    fn small_messages_are_not_compressed() {
+        let mut options = EncodeOptions::new();
+        options.set_compression_threshold(0);
+        let payload = encode_payload(&42u64, HashMap::new(), &options).unwrap();
+        assert_eq!(&[MAGIC, VERSION], &payload[..2]);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn decodes_compressed_messages() {
+        let message = vec![42u64; 1024];
+        let compression = Some(Compression::Zstd);
+        let mut options = EncodeOptions::new();
+        options.set_compression(compression);
+        options.set_compression_threshold(64);
+        let small = encode_payload(&42u64, HashMap::new(), &options).unwrap();
+        assert_eq!(&[MAGIC, VERSION], &small[..2]);
+        let large = encode_payload(&message, HashMap::new(), &options).unwrap();
+        assert_eq!(&[MAGIC, VERSION_COMPRESSED, 0, 1], &large[..4]);
+
+        let (decoded, envelope) =
+            decode_payload::<Vec<u64>, _>(&large, &MsgPackCodec, None).unwrap();
+        assert_eq!(message, decoded);
+        assert_eq!(compression, envelope.unwrap().compression());
+    }
+
+    #[test]
+    fn parses_signed_payloads() {
+        let envelope = Envelope::new(HashMap::new());
+        let payload = envelope.wrap(&[42]).unwrap();
+        let signed = join_signature("k1", &[7; SIGNATURE_SIZE], &payload);
+
+        let (key_id, signature, rest) = split_signature(&signed).unwrap().unwrap();
+        assert_eq!(("k1", &[7; SIGNATURE_SIZE][..]), (key_id, signature));
+        assert_eq!(payload, rest);
+        assert_eq!(
+            (Some(envelope), &[42][..]),
+            Envelope::parse(&signed).unwrap()
+        );
+        assert_eq!(None, split_signature(&payload).unwrap());
+        assert!(split_signature(&signed[..10]).unwrap_err().is_poison());
+    }
+
+    #[test]
+    fn bare_payloads_starting_with_the_magic_byte_are_unchanged() {
+        for payload in [
+            &[MAGIC][..],
+            &[MAGIC, 42, 1, 2],
+            &[MAGIC, VERSION, 1, 2],
+            &[MAGIC, VERSION_COMPRESSED, 0],
+            &[MAGIC, VERSION_SIGNED, 1, 2],
+        ] {
+            assert_eq!((None, payload), Envelope::parse(payload).unwrap());
+        }
+        let signed = join_signature("k1", &[7; SIGNATURE_SIZE], &[1, 2]);
+        assert_eq!((None, &signed[..]), Envelope::parse(&signed).unwrap());
+    }
+
+    #[test]
+    fn decodes_both_formats() {
+        let body = rmp_serde::encode::to_vec(&42u64).unwrap();
+        let wrapped = Envelope::new(HashMap::new()).wrap(&body).unwrap();
+
+        let (m, e) = decode_payload::<u64, _>(&body, &MsgPackCodec, None).unwrap();
+        assert_eq!((42, None), (m, e));
+        let (m, e) =
+            decode_payload::<u64, _>(&wrapped, &MsgPackCodec, None).unwrap();
+        assert_eq!(42, m);
+        assert!(e.is_some());
+    }
+
+    #[cfg(feature = "aes-gcm")]
+    #[test]
+    fn decrypts_encrypted_messages() {
+        use crate::encryption::Cipher;
+
+        let keyring = Keyring::new("k1", Cipher::Aes256Gcm, [7; 32]);
+        let mut options = EncodeOptions::new();
+        options.set_keyring(keyring.clone());
+        let payload = encode_payload(&42u64, HashMap::new(), &options).unwrap();
+
+        let (m, e) =
+            decode_payload::<u64, _>(&payload, &MsgPackCodec, Some(&keyring))
+                .unwrap();
+        assert_eq!(42, m);
+        assert_eq!(Some("k1"), e.unwrap().key_id());
+        let err =
+            decode_payload::<u64, _>(&payload, &MsgPackCodec, None).unwrap_err();
+        assert!(err.is_decrypt());
+        let other = Keyring::new("k1", Cipher::Aes256Gcm, [8; 32]);
+        let err = decode_payload::<u64, _>(&payload, &MsgPackCodec, Some(&other))
+            .unwrap_err();
+        assert!(err.is_decrypt());
+    }
+}
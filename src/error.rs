@@ -0,0 +1,116 @@
+use redis::{RedisError, Value};
+use std::error;
+use std::fmt;
+use std::io;
+
+/// Errors returned by consumers, producers, message guards and the garbage
+/// collector.
+#[derive(Debug)]
+pub enum Error {
+    /// The underlying Redis command failed. This usually signals a transient
+    /// connection problem and the operation can be retried.
+    Redis(RedisError),
+    /// The message could not be encoded before being pushed.
+    Encode(Box<dyn error::Error + Send + Sync>),
+    /// The payload fetched from Redis could not be decoded into a message.
+    /// Retrying will not help: the payload is a poison message.
+    Decode(Box<dyn error::Error + Send + Sync>),
+    /// The payload fetched from Redis could not be decrypted, because its key
+    /// is unknown or it failed authentication. The consumer moves it to the
+    /// dead-letter queue.
+    Decrypt(Box<dyn error::Error + Send + Sync>),
+    /// The payload fetched from Redis is unsigned or its signature is invalid.
+    /// The consumer handles it according to its signature policy.
+    Signature(Box<dyn error::Error + Send + Sync>),
+    /// Redis replied with a value of an unexpected type.
+    UnexpectedReply(Value),
+    /// An I/O error unrelated to Redis, e.g. while installing signal handlers.
+    Io(io::Error),
+}
+
+impl Error {
+    /// Check if the error originated from Redis, i.e. it is transient.
+    pub fn is_redis(&self) -> bool {
+        matches!(*self, Error::Redis(_))
+    }
+
+    /// Check if the error was caused by a payload that cannot be decoded.
+    pub fn is_poison(&self) -> bool {
+        matches!(
+            *self,
+            Error::Decode(_)
+                | Error::Decrypt(_)
+                | Error::Signature(_)
+                | Error::UnexpectedReply(_)
+        )
+    }
+
+    /// Check if the error was caused by a payload that cannot be decrypted.
+    pub fn is_decrypt(&self) -> bool {
+        matches!(*self, Error::Decrypt(_))
+    }
+
+    /// Check if the error was caused by a payload that is unsigned or whose
+    /// signature is invalid.
+    pub fn is_signature(&self) -> bool {
+        matches!(*self, Error::Signature(_))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Redis(ref e) => write!(f, "redis error: {}", e),
+            Error::Encode(ref e) => write!(f, "failed to encode message: {}", e),
+            Error::Decode(ref e) => write!(f, "failed to decode message: {}", e),
+            Error::Decrypt(ref e) => {
+                write!(f, "failed to decrypt message: {}", e)
+            }
+            Error::Signature(ref e) => {
+                write!(f, "failed to verify message: {}", e)
+            }
+            Error::UnexpectedReply(ref v) => {
+                write!(f, "unexpected reply from redis: {:?}", v)
+            }
+            Error::Io(ref e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Redis(ref e) => Some(e),
+            Error::Encode(ref e)
+            | Error::Decode(ref e)
+            | Error::Decrypt(ref e)
+            | Error::Signature(ref e) => Some(&**e),
+            Error::Io(ref e) => Some(e),
+            Error::UnexpectedReply(_) => None,
+        }
+    }
+}
+
+impl From<RedisError> for Error {
+    fn from(e: RedisError) -> Error {
+        Error::Redis(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<rmp_serde::encode::Error> for Error {
+    fn from(e: rmp_serde::encode::Error) -> Error {
+        Error::Encode(Box::new(e))
+    }
+}
+
+impl From<rmp_serde::decode::Error> for Error {
+    fn from(e: rmp_serde::decode::Error) -> Error {
+        Error::Decode(Box::new(e))
+    }
+}
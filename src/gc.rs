@@ -1,55 +1,427 @@
-use crate::consumer;
-use redis::{Commands, RedisResult, Value};
+use crate::consumer::{self, now_millis};
+use crate::error::Error;
+use crate::lease::Lease;
+use redis::{Commands, Script};
 use std::cell::RefCell;
+use std::collections::HashMap;
+
+// Move up to ARGV[1] messages from the unacked queue to the processing queue,
+// or if ARGV[2] is 1 and the consumer recorded its source queues, back to the
+// source queue recorded in the origins hash KEYS[5], defaulting to the main
+// one. Their rejection reasons are removed from the hash KEYS[6]. Returns the
+// number of moved messages, or -1 if the fencing token in KEYS[4] is no longer
+// ARGV[3].
+const COLLECT_SCRIPT: &str = r"
+if ARGV[3] ~= '' and redis.call('GET', KEYS[4]) ~= ARGV[3] then
+    return -1
+end
+local main = false
+if ARGV[2] == '1' then
+    main = redis.call('LINDEX', KEYS[3], 0)
+end
+local limit = tonumber(ARGV[1])
+local n = 0
+while n < limit do
+    local payload = redis.call('RPOP', KEYS[1])
+    if not payload then
+        break
+    end
+    local destination = KEYS[2]
+    if main then
+        destination = redis.call('HGET', KEYS[5], payload) or main
+        redis.call('HDEL', KEYS[5], payload)
+    end
+    redis.call('HDEL', KEYS[6], payload)
+    redis.call('LPUSH', destination, payload)
+    n = n + 1
+end
+return n
+";
+
+// Move up to ARGV[2] messages whose visibility deadline is not after ARGV[1]
+// from the processing queue back to the source queue recorded in the origins
+// hash KEYS[5], defaulting to the main one. The deadlines are keyed by envelope
+// id, mapped to the payloads by the inflight hash KEYS[6]. Returns the number
+// of requeued messages, or -1 if the fencing token in KEYS[4] is no longer
+// ARGV[3] or the consumer did not record its source queue.
+const EXPIRE_SCRIPT: &str = r"
+if ARGV[3] ~= '' and redis.call('GET', KEYS[4]) ~= ARGV[3] then
+    return -1
+end
+local source = redis.call('LINDEX', KEYS[3], 0)
+if not source then
+    return -1
+end
+local expired = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1], 'LIMIT', 0, ARGV[2])
+local n = 0
+for _, member in ipairs(expired) do
+    -- Messages without an envelope are keyed by their payload.
+    local payload = redis.call('HGET', KEYS[6], member) or member
+    -- The message may have been acked in the meantime, or requeued when its
+    -- consumer shut down.
+    if redis.call('LREM', KEYS[2], 1, payload) == 1 then
+        local destination = redis.call('HGET', KEYS[5], payload) or source
+        redis.call('HDEL', KEYS[5], payload)
+        redis.call('LPUSH', destination, payload)
+        n = n + 1
+    end
+    redis.call('ZREM', KEYS[1], member)
+    redis.call('HDEL', KEYS[6], member)
+end
+return n
+";
+
+// If the consumer sent heartbeats but the last one expired, move up to ARGV[3]
+// messages of its processing then unacked queues back to the source queue
+// recorded in the origins hash KEYS[9], defaulting to the main one, and
+// deregister it once both are empty, removing its rejection reasons. Returns
+// the number of requeued messages, or -1 if the consumer is alive, never sent
+// a heartbeat or did not record its source queue, or if the fencing token in
+// KEYS[7] is no longer ARGV[2].
+const REAP_SCRIPT: &str = r"
+if ARGV[2] ~= '' and redis.call('GET', KEYS[7]) ~= ARGV[2] then
+    return -1
+end
+if redis.call('EXISTS', KEYS[1]) == 1 then
+    return -1
+end
+if redis.call('HEXISTS', KEYS[2], ARGV[1]) == 0 then
+    return -1
+end
+local source = redis.call('LINDEX', KEYS[3], 0)
+if not source then
+    return -1
+end
+local limit = tonumber(ARGV[3])
+local n = 0
+for i = 4, 5 do
+    while n < limit do
+        local payload = redis.call('RPOP', KEYS[i])
+        if not payload then
+            break
+        end
+        local destination = redis.call('HGET', KEYS[9], payload) or source
+        redis.call('HDEL', KEYS[9], payload)
+        redis.call('LPUSH', destination, payload)
+        n = n + 1
+    end
+end
+if n < limit then
+    redis.call('SREM', KEYS[6], ARGV[1])
+    redis.call('HDEL', KEYS[2], ARGV[1])
+    redis.call('DEL', KEYS[3], KEYS[8], KEYS[9], KEYS[10], KEYS[11])
+end
+return n
+";
+
+/// Where the garbage collector moves the unacked messages of the live
+/// consumers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CollectMode {
+    /// Move them to the *processing* queue of the consumer that rejected them.
+    Processing,
+    /// Move them back to the *source* queue they were fetched from, so that
+    /// any consumer can pick them up. The source queues of a consumer are
+    /// recorded when it registers: messages of consumers that did not record
+    /// them are moved to their *processing* queue instead.
+    Source,
+}
 
 pub struct GC {
+    mode: CollectMode,
+    batch_size: u64,
+    collect_script: Script,
+    reap_script: Script,
+    expire_script: Script,
+    lease: Option<Lease>,
     client: RefCell<redis::Connection>,
 }
 
+/// The key and value of the fencing token checked by the collection scripts,
+/// both empty if the collector has no lease.
+type Fence = (String, String);
+
 impl GC {
     pub fn new(client: redis::Connection) -> GC {
         GC {
+            mode: CollectMode::Processing,
+            batch_size: 100,
+            collect_script: Script::new(COLLECT_SCRIPT),
+            reap_script: Script::new(REAP_SCRIPT),
+            expire_script: Script::new(EXPIRE_SCRIPT),
+            lease: None,
             client: RefCell::new(client),
         }
     }
 
-    pub fn collect_one(&self, consumer_name: &str) -> RedisResult<u64> {
-        let n: u64 = self.client.borrow_mut().llen(
-            consumer::UNACKED_QUEUE_KEY.replace("{consumer}", consumer_name),
-        )?;
+    /// Set where unacked messages are moved. Defaults to
+    /// `CollectMode::Processing`.
+    pub fn set_mode(&mut self, mode: CollectMode) {
+        self.mode = mode;
+    }
+
+    /// Get the collect mode.
+    pub fn mode(&self) -> CollectMode {
+        self.mode
+    }
+
+    /// Set the maximum number of messages moved by each script invocation.
+    /// Larger batches need fewer round trips, but block Redis for longer.
+    /// Defaults to 100.
+    pub fn set_batch_size(&mut self, batch_size: u64) {
+        self.batch_size = batch_size.max(1);
+    }
+
+    /// Get the batch size.
+    pub fn batch_size(&self) -> u64 {
+        self.batch_size
+    }
+
+    /// Only collect while holding the given lease, so that a single collector
+    /// is active at a time even if every instance of a service runs one.
+    ///
+    /// The lease is acquired or renewed on every collection, so its TTL should
+    /// be longer than the interval between collections. Collections are
+    /// fenced: a collector that lost the lease stops moving messages as soon
+    /// as another one acquires it.
+    pub fn set_lease(&mut self, lease: Lease) {
+        self.lease = Some(lease);
+    }
+
+    /// Get the lease of the collector.
+    pub fn lease(&self) -> Option<&Lease> {
+        self.lease.as_ref()
+    }
 
-        if n == 0 {
-            return Ok(0);
+    /// Acquire or renew the lease, if any. Returns `None` if another collector
+    /// holds it.
+    fn fence(&self) -> Result<Option<Fence>, Error> {
+        let lease = match &self.lease {
+            Some(lease) => lease,
+            None => return Ok(Some((String::new(), String::new()))),
+        };
+        Ok(lease
+            .acquire()?
+            .map(|token| (lease.token_key().to_string(), token.to_string())))
+    }
+
+    /// Collect the unacked messages of the given consumer.
+    ///
+    /// Returns the number of collected messages, which is zero if another
+    /// collector holds the lease.
+    pub fn collect_one(&self, consumer_name: &str) -> Result<u64, Error> {
+        match self.fence()? {
+            Some(fence) => self.collect_one_fenced(consumer_name, &fence),
+            None => Ok(0),
         }
+    }
 
-        // It's doesn't matter if more elements are added after the call to LLEN
-        // returns, as this method is designed to be called periodically (and
-        // should be). It also does not matter if some elements are removed,
-        // because we are using RPOPLPUSH here, which is not blocking.
+    fn collect_one_fenced(
+        &self,
+        consumer_name: &str,
+        fence: &Fence,
+    ) -> Result<u64, Error> {
+        let mode = match self.mode {
+            CollectMode::Processing => 0,
+            CollectMode::Source => 1,
+        };
         let mut total: u64 = 0;
-        for _ in 0..n {
-            let res: RedisResult<Value> = self.client.borrow_mut().rpoplpush(
-                consumer::UNACKED_QUEUE_KEY.replace("{consumer}", consumer_name),
-                consumer::PROCESSING_QUEUE_KEY
-                    .replace("{consumer}", consumer_name),
-            );
-            match res {
-                Err(e) => return Err(e),
-                Ok(Value::Nil) => return Ok(total),
-                Ok(_) => (),
+        loop {
+            let n: i64 = self
+                .collect_script
+                .key(
+                    consumer::UNACKED_QUEUE_KEY
+                        .replace("{consumer}", consumer_name),
+                )
+                .key(
+                    consumer::PROCESSING_QUEUE_KEY
+                        .replace("{consumer}", consumer_name),
+                )
+                .key(consumer::SOURCES_KEY.replace("{consumer}", consumer_name))
+                .key(fence.0.as_str())
+                .key(consumer::ORIGINS_KEY.replace("{consumer}", consumer_name))
+                .key(consumer::REJECTIONS_KEY.replace("{consumer}", consumer_name))
+                .arg(self.batch_size)
+                .arg(mode)
+                .arg(fence.1.as_str())
+                .invoke(&mut *self.client.borrow_mut())?;
+            // The lease was taken over by another collector.
+            if n < 0 {
+                return Ok(total);
+            }
+            total += n as u64;
+            // Each batch is atomic, so a short one means the queue was empty,
+            // even with several collectors running concurrently.
+            if (n as u64) < self.batch_size {
+                return Ok(total);
             }
-            total += 1;
         }
-        Ok(total)
     }
 
-    pub fn collect(&self) -> RedisResult<u64> {
+    /// Move the messages of the given consumer whose visibility deadline
+    /// passed back to the source queues they were fetched from (see
+    /// `Consumer::set_visibility_timeout()`).
+    ///
+    /// Returns the number of requeued messages.
+    pub fn collect_expired_one(&self, consumer_name: &str) -> Result<u64, Error> {
+        match self.fence()? {
+            Some(fence) => self.collect_expired_one_fenced(consumer_name, &fence),
+            None => Ok(0),
+        }
+    }
+
+    fn collect_expired_one_fenced(
+        &self,
+        consumer_name: &str,
+        fence: &Fence,
+    ) -> Result<u64, Error> {
+        let mut total: u64 = 0;
+        loop {
+            let n: i64 = self
+                .expire_script
+                .key(consumer::DEADLINES_KEY.replace("{consumer}", consumer_name))
+                .key(
+                    consumer::PROCESSING_QUEUE_KEY
+                        .replace("{consumer}", consumer_name),
+                )
+                .key(consumer::SOURCES_KEY.replace("{consumer}", consumer_name))
+                .key(fence.0.as_str())
+                .key(consumer::ORIGINS_KEY.replace("{consumer}", consumer_name))
+                .key(consumer::INFLIGHT_KEY.replace("{consumer}", consumer_name))
+                .arg(now_millis())
+                .arg(self.batch_size)
+                .arg(fence.1.as_str())
+                .invoke(&mut *self.client.borrow_mut())?;
+            if n < 0 {
+                return Ok(total);
+            }
+            total += n as u64;
+            if (n as u64) < self.batch_size {
+                return Ok(total);
+            }
+        }
+    }
+
+    /// Check whether the heartbeat of the given consumer expired, and if so
+    /// move the messages of its *processing* and *unack* queues back to the
+    /// source queues they were fetched from and deregister it.
+    ///
+    /// Returns the number of requeued messages, or `None` if the consumer is
+    /// alive or another collector holds the lease. Consumers that never sent a
+    /// heartbeat are considered alive.
+    pub fn collect_dead_one(
+        &self,
+        consumer_name: &str,
+    ) -> Result<Option<u64>, Error> {
+        match self.fence()? {
+            Some(fence) => self.collect_dead_one_fenced(consumer_name, &fence),
+            None => Ok(None),
+        }
+    }
+
+    fn collect_dead_one_fenced(
+        &self,
+        consumer_name: &str,
+        fence: &Fence,
+    ) -> Result<Option<u64>, Error> {
+        let mut total: Option<u64> = None;
+        loop {
+            let n: i64 = self
+                .reap_script
+                .key(consumer::HEARTBEAT_KEY.replace("{consumer}", consumer_name))
+                .key(consumer::HEARTBEATS_KEY)
+                .key(consumer::SOURCES_KEY.replace("{consumer}", consumer_name))
+                .key(
+                    consumer::PROCESSING_QUEUE_KEY
+                        .replace("{consumer}", consumer_name),
+                )
+                .key(
+                    consumer::UNACKED_QUEUE_KEY
+                        .replace("{consumer}", consumer_name),
+                )
+                .key(consumer::CONSUMERS_KEY)
+                .key(fence.0.as_str())
+                .key(consumer::DEADLINES_KEY.replace("{consumer}", consumer_name))
+                .key(consumer::ORIGINS_KEY.replace("{consumer}", consumer_name))
+                .key(consumer::INFLIGHT_KEY.replace("{consumer}", consumer_name))
+                .key(consumer::REJECTIONS_KEY.replace("{consumer}", consumer_name))
+                .arg(consumer_name)
+                .arg(fence.1.as_str())
+                .arg(self.batch_size)
+                .invoke(&mut *self.client.borrow_mut())?;
+            // The consumer is alive, or the lease was taken over by another
+            // collector.
+            if n < 0 {
+                return Ok(total);
+            }
+            let sum = total.get_or_insert(0);
+            *sum += n as u64;
+            // The consumer is deregistered by the batch that empties its
+            // queues.
+            if (n as u64) < self.batch_size {
+                return Ok(Some(*sum));
+            }
+        }
+    }
+
+    /// Requeue the messages of all the registered consumers whose heartbeat
+    /// expired, and deregister them.
+    ///
+    /// Returns the number of requeued messages.
+    pub fn collect_dead(&self) -> Result<u64, Error> {
+        match self.fence()? {
+            Some(fence) => self.collect_dead_fenced(&fence),
+            None => Ok(0),
+        }
+    }
+
+    fn collect_dead_fenced(&self, fence: &Fence) -> Result<u64, Error> {
         let vals: Vec<String> =
             self.client.borrow_mut().smembers(consumer::CONSUMERS_KEY)?;
         let mut total: u64 = 0;
         for name in vals {
-            total += self.collect_one(name.as_str()).unwrap_or(0);
+            total += self
+                .collect_dead_one_fenced(name.as_str(), fence)
+                .unwrap_or(None)
+                .unwrap_or(0);
         }
         Ok(total)
     }
+
+    /// Requeue the messages of the dead consumers, then requeue the expired
+    /// messages and collect the unacked messages of the live ones.
+    ///
+    /// Returns zero without collecting anything if another collector holds the
+    /// lease.
+    pub fn collect(&self) -> Result<u64, Error> {
+        Ok(self.collect_each()?.values().sum())
+    }
+
+    /// Like `GC::collect()`, but return the number of messages requeued or
+    /// collected for each consumer. Consumers without any are left out.
+    pub fn collect_each(&self) -> Result<HashMap<String, u64>, Error> {
+        let mut counts = HashMap::new();
+        let fence = match self.fence()? {
+            Some(fence) => fence,
+            None => return Ok(counts),
+        };
+        let vals: Vec<String> =
+            self.client.borrow_mut().smembers(consumer::CONSUMERS_KEY)?;
+        for name in vals {
+            let n = match self.collect_dead_one_fenced(name.as_str(), &fence) {
+                Ok(Some(n)) => n,
+                _ => {
+                    self.collect_expired_one_fenced(name.as_str(), &fence)
+                        .unwrap_or(0)
+                        + self
+                            .collect_one_fenced(name.as_str(), &fence)
+                            .unwrap_or(0)
+                }
+            };
+            if n > 0 {
+                counts.insert(name, n);
+            }
+        }
+        Ok(counts)
+    }
 }
@@ -0,0 +1,143 @@
+use crate::consumer::{lock, now_millis};
+use crate::gc::GC;
+use std::collections::HashMap;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Statistics of a `GcRunner`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GcStats {
+    runs: u64,
+    errors: u64,
+    last_run_at: Option<u64>,
+    last_error: Option<String>,
+    recovered: HashMap<String, u64>,
+    last_recovered_at: HashMap<String, u64>,
+}
+
+impl GcStats {
+    /// Get the number of collections run so far.
+    pub fn runs(&self) -> u64 {
+        self.runs
+    }
+
+    /// Get the number of collections that failed.
+    pub fn errors(&self) -> u64 {
+        self.errors
+    }
+
+    /// Get the time the last collection ended, in milliseconds since the Unix
+    /// epoch.
+    pub fn last_run_at(&self) -> Option<u64> {
+        self.last_run_at
+    }
+
+    /// Get the error of the last collection, if it failed.
+    pub fn last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
+    }
+
+    /// Get the total number of messages recovered from each consumer.
+    pub fn recovered(&self) -> &HashMap<String, u64> {
+        &self.recovered
+    }
+
+    /// Get the total number of messages recovered from all the consumers.
+    pub fn total_recovered(&self) -> u64 {
+        self.recovered.values().sum()
+    }
+
+    /// Get the time messages were last recovered from each consumer, in
+    /// milliseconds since the Unix epoch.
+    pub fn last_recovered_at(&self) -> &HashMap<String, u64> {
+        &self.last_recovered_at
+    }
+
+    fn record(&mut self, res: Result<HashMap<String, u64>, String>) {
+        let now = now_millis();
+        self.runs += 1;
+        self.last_run_at = Some(now);
+        match res {
+            Ok(counts) => {
+                self.last_error = None;
+                for (name, n) in counts {
+                    *self.recovered.entry(name.clone()).or_insert(0) += n;
+                    self.last_recovered_at.insert(name, now);
+                }
+            }
+            Err(e) => {
+                self.errors += 1;
+                self.last_error = Some(e);
+            }
+        }
+    }
+}
+
+/// Runs a garbage collector periodically in a background thread.
+///
+/// The first collection runs straight away. The runner stops when
+/// `GcRunner::stop()` is called or when it is dropped, without waiting for
+/// the end of the current interval.
+pub struct GcRunner {
+    stats: Arc<Mutex<GcStats>>,
+    // Dropping the sender wakes the thread up and makes it exit.
+    sender: Option<Sender<()>>,
+    handle: Option<JoinHandle<GC>>,
+}
+
+impl GcRunner {
+    /// Start collecting with the given garbage collector every `interval`, in
+    /// a new thread that owns it.
+    ///
+    /// Collections run back to back: the interval is measured from the end of
+    /// one to the start of the next. Errors are recorded in the statistics
+    /// instead of stopping the runner. Stopping or dropping the runner wakes
+    /// the thread up and joins it, so it blocks until the current collection,
+    /// if any, ends.
+    pub fn start(gc: GC, interval: Duration) -> GcRunner {
+        let stats = Arc::new(Mutex::new(GcStats::default()));
+        let (sender, receiver) = mpsc::channel();
+        let thread_stats = stats.clone();
+        let handle = thread::spawn(move || {
+            loop {
+                let res = gc.collect_each().map_err(|e| e.to_string());
+                lock(&thread_stats).record(res);
+                match receiver.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => (),
+                    _ => break,
+                }
+            }
+            gc
+        });
+        GcRunner {
+            stats,
+            sender: Some(sender),
+            handle: Some(handle),
+        }
+    }
+
+    /// Get a snapshot of the statistics.
+    pub fn stats(&self) -> GcStats {
+        lock(&self.stats).clone()
+    }
+
+    /// Stop the runner, waiting for the current collection to end.
+    ///
+    /// Returns the garbage collector, unless it panicked.
+    pub fn stop(mut self) -> Option<GC> {
+        self.join()
+    }
+
+    fn join(&mut self) -> Option<GC> {
+        self.sender.take();
+        self.handle.take().and_then(|handle| handle.join().ok())
+    }
+}
+
+impl Drop for GcRunner {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
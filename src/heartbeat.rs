@@ -0,0 +1,105 @@
+use redis::RedisResult;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Record a heartbeat of the consumer, both in the heartbeats hash and in its
+/// own key expiring after `ttl`.
+///
+/// Returns the timestamp of the heartbeat, in milliseconds since the Unix
+/// epoch.
+pub(crate) fn beat(
+    con: &mut dyn redis::ConnectionLike,
+    heartbeats_key: &str,
+    heartbeat_key: &str,
+    consumer_name: &str,
+    ttl: Duration,
+) -> (u128, RedisResult<()>) {
+    let (ts, pipe) = pipe(heartbeats_key, heartbeat_key, consumer_name, ttl);
+    (ts, pipe.query(con))
+}
+
+/// Build the pipeline recording a heartbeat, shared by the sync and async
+/// consumers.
+///
+/// Returns the timestamp of the heartbeat and the pipeline.
+pub(crate) fn pipe(
+    heartbeats_key: &str,
+    heartbeat_key: &str,
+    consumer_name: &str,
+    ttl: Duration,
+) -> (u128, redis::Pipeline) {
+    let ts = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_millis(),
+        Err(_) => 0,
+    };
+    let mut pipe = redis::pipe();
+    pipe.cmd("HSET")
+        .arg(heartbeats_key)
+        .arg(consumer_name)
+        .arg(ts.to_string())
+        .ignore()
+        .cmd("SET")
+        .arg(heartbeat_key)
+        .arg(ts.to_string())
+        .arg("PX")
+        .arg(ttl.as_millis().to_string())
+        .ignore();
+    (ts, pipe)
+}
+
+/// A background thread sending the heartbeats of a consumer.
+///
+/// The thread exits when the consumer is stopped, or as soon as this value is
+/// dropped.
+pub(crate) struct Heartbeat {
+    // Dropping the sender wakes the thread up and makes it exit.
+    sender: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Heartbeat {
+    pub(crate) fn start(
+        mut client: redis::Connection,
+        heartbeats_key: String,
+        heartbeat_key: String,
+        consumer_name: String,
+        interval: Duration,
+        ttl: Duration,
+        stopped: Arc<AtomicBool>,
+    ) -> Heartbeat {
+        let (sender, receiver) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            while !stopped.load(Ordering::SeqCst) {
+                // Errors are ignored: the next heartbeat is sent anyway, and
+                // the consumer is only considered dead once the TTL expires.
+                let _ = beat(
+                    &mut client,
+                    &heartbeats_key,
+                    &heartbeat_key,
+                    &consumer_name,
+                    ttl,
+                );
+                match receiver.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => (),
+                    _ => break,
+                }
+            }
+        });
+        Heartbeat {
+            sender: Some(sender),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
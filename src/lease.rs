@@ -0,0 +1,123 @@
+use crate::error::Error;
+use redis::Script;
+use std::cell::{Cell, RefCell};
+use std::process;
+use std::time::Duration;
+use uuid::Uuid;
+
+pub const LEASE_KEY: &str = "orizuru:leases:{lease}";
+pub const LEASE_TOKEN_KEY: &str = "orizuru:leases:{lease}:token";
+
+// Acquire the lease if it is free, or renew it if it is already held by this
+// holder. Returns the fencing token, or nil if another holder has the lease.
+const ACQUIRE_SCRIPT: &str = r"
+if redis.call('SET', KEYS[1], ARGV[1], 'NX', 'PX', ARGV[2]) then
+    return redis.call('INCR', KEYS[2])
+end
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    redis.call('PEXPIRE', KEYS[1], ARGV[2])
+    return tonumber(redis.call('GET', KEYS[2]))
+end
+return false
+";
+
+// Release the lease if it is held by this holder.
+const RELEASE_SCRIPT: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+";
+
+/// A lease that at most one holder owns at a time, e.g. to elect the single
+/// active garbage collector.
+///
+/// The lease expires unless it is renewed within its TTL, at which point any
+/// other holder can take it over. Each acquisition increments a fencing token,
+/// so that the writes of a holder that lost the lease without noticing can be
+/// rejected.
+pub struct Lease {
+    key: String,
+    token_key: String,
+    holder: String,
+    ttl: Duration,
+    token: Cell<Option<u64>>,
+    acquire_script: Script,
+    release_script: Script,
+    client: RefCell<redis::Connection>,
+}
+
+impl Lease {
+    /// Create a lease with the given name. The holder is identified by a
+    /// random id, unique across hosts and processes.
+    pub fn new(name: &str, ttl: Duration, client: redis::Connection) -> Lease {
+        let host = hostname::get()
+            .map(|h| h.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| "localhost".into());
+        Lease {
+            key: LEASE_KEY.replace("{lease}", name),
+            token_key: LEASE_TOKEN_KEY.replace("{lease}", name),
+            holder: format!("{}:{}:{}", host, process::id(), Uuid::new_v4()),
+            ttl,
+            token: Cell::new(None),
+            acquire_script: Script::new(ACQUIRE_SCRIPT),
+            release_script: Script::new(RELEASE_SCRIPT),
+            client: RefCell::new(client),
+        }
+    }
+
+    /// Get the lease key.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Get the key of the fencing token.
+    pub fn token_key(&self) -> &str {
+        &self.token_key
+    }
+
+    /// Get the id of this holder.
+    pub fn holder(&self) -> &str {
+        &self.holder
+    }
+
+    /// Get the TTL of the lease.
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Get the fencing token obtained by the last successful call to
+    /// `Lease::acquire()`.
+    pub fn token(&self) -> Option<u64> {
+        self.token.get()
+    }
+
+    /// Acquire the lease, or renew it if it is already held by this holder.
+    ///
+    /// Returns the fencing token, or `None` if another holder has the lease.
+    pub fn acquire(&self) -> Result<Option<u64>, Error> {
+        let token: Option<u64> = self
+            .acquire_script
+            .key(self.key.as_str())
+            .key(self.token_key.as_str())
+            .arg(self.holder.as_str())
+            .arg(self.ttl.as_millis() as u64)
+            .invoke(&mut *self.client.borrow_mut())?;
+        self.token.set(token);
+        Ok(token)
+    }
+
+    /// Release the lease, so that another holder can take it over straight
+    /// away.
+    ///
+    /// Returns `false` if the lease was not held by this holder.
+    pub fn release(&self) -> Result<bool, Error> {
+        self.token.set(None);
+        let n: u64 = self
+            .release_script
+            .key(self.key.as_str())
+            .arg(self.holder.as_str())
+            .invoke(&mut *self.client.borrow_mut())?;
+        Ok(n == 1)
+    }
+}
@@ -1,14 +1,62 @@
+#[cfg(feature = "aio")]
+mod aio;
+mod codec;
+mod compression;
 mod consumer;
+mod dead_letter;
+mod delayed;
+mod encryption;
+mod envelope;
+mod error;
 mod gc;
+mod gc_runner;
+mod heartbeat;
+mod lease;
 mod message;
+mod pool;
+mod priority;
 mod producer;
+#[cfg(feature = "protobuf")]
+mod protobuf;
+mod retry;
+mod scheduler;
+mod shutdown;
+mod signing;
 
+#[cfg(feature = "aio")]
+pub use aio::{AsyncConsumer, AsyncMessageGuard, AsyncProducer};
+#[cfg(feature = "bincode")]
+pub use codec::BincodeCodec;
+#[cfg(feature = "cbor")]
+pub use codec::CborCodec;
+#[cfg(feature = "json")]
+pub use codec::JsonCodec;
+#[cfg(feature = "protobuf")]
+pub use codec::ProtobufCodec;
+pub use codec::{Codec, MsgPackCodec};
+pub use compression::Compression;
 pub use consumer::{
-    Consumer, CONSUMERS_KEY, HEARTBEATS_KEY, HEARTBEAT_KEY, PROCESSING_QUEUE_KEY,
-    UNACKED_QUEUE_KEY,
+    Consumer, SourceOrder, StopHandle, CONSUMERS_KEY, DEADLINES_KEY,
+    DEAD_LETTER_QUEUE_KEY, DELAYED_QUEUES_KEY, DELAYED_QUEUE_KEY, HEARTBEATS_KEY,
+    HEARTBEAT_KEY, INFLIGHT_KEY, ORIGINS_KEY, PROCESSING_QUEUE_KEY,
+    REJECTIONS_KEY, SOURCES_KEY, UNACKED_QUEUE_KEY,
 };
-pub use gc::GC;
+pub use dead_letter::{DeadLetter, DeadLetterQueue};
+pub use encryption::{Cipher, Keyring};
+pub use envelope::{EncodeOptions, Envelope};
+pub use error::Error;
+pub use gc::{CollectMode, GC};
+pub use gc_runner::{GcRunner, GcStats};
+pub use lease::{Lease, LEASE_KEY, LEASE_TOKEN_KEY};
 pub use message::{
     MessageDecodable, MessageEncodable, MessageGuard, MessageState,
 };
+pub use pool::WorkerPool;
+pub use priority::{Priority, PriorityMode, PriorityProducer};
 pub use producer::Producer;
+#[cfg(feature = "protobuf")]
+pub use protobuf::Protobuf;
+pub use retry::{RetryOutcome, RetryPolicy};
+pub use scheduler::Scheduler;
+pub use shutdown::{Shutdown, ShutdownAction};
+pub use signing::{SignaturePolicy, Signer};
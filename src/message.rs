@@ -1,8 +1,18 @@
-use redis::{Commands, RedisResult, Value};
+use crate::codec::{Codec, MsgPackCodec};
+use crate::consumer::{
+    lock, now_millis, Consumer, DEAD_LETTER_QUEUE_KEY, DELAYED_QUEUES_KEY,
+    DELAYED_QUEUE_KEY,
+};
+use crate::dead_letter::DeadLetter;
+use crate::envelope::{self, Envelope};
+use crate::error::Error;
+use crate::retry::RetryOutcome;
+use redis::Value;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use std::cell::RefCell;
 use std::ops::{Deref, Drop};
+use std::sync::Mutex;
+use std::time::Duration;
 
 #[derive(Debug, PartialEq)]
 pub enum MessageState {
@@ -10,12 +20,14 @@ pub enum MessageState {
     Acked,
     Rejected,
     Pushed,
+    Retried,
+    DeadLettered,
 }
 
 /// Message objects that can be reconstructed from the data stored in Redis.
 ///
 /// Implemented for all `Deserialize` objects by default by relying on Msgpack
-/// decoding.
+/// decoding, or on the codec recorded in the envelope of the message.
 pub trait MessageDecodable
 where
     Self: Sized,
@@ -24,111 +36,371 @@ where
     ///
     /// In the default implementation, the string value is decoded by assuming
     /// it was encoded through the Msgpack encoding.
-    fn decode_message(value: &Value) -> Result<Self, &'static str>;
+    fn decode_message(value: &Value) -> Result<Self, Error>;
+
+    /// Decode the given Redis value into a message encoded with the given
+    /// codec.
+    ///
+    /// The default implementation only supports Msgpack, through
+    /// `decode_message`.
+    fn decode_message_with<C: Codec>(
+        value: &Value,
+        codec: &C,
+    ) -> Result<Self, Error> {
+        if codec.tag() != MsgPackCodec.tag() {
+            return Err(Error::Decode(
+                format!("unsupported codec {:?}", codec).into(),
+            ));
+        }
+        Self::decode_message(value)
+    }
 }
 
 /// Message objects that can be encoded to a string to be stored in Redis.
 ///
-/// Implemented for all `Serialize` objects by default by encoding with Msgpack.
+/// Implemented for all `Serialize` objects by default by encoding with Msgpack,
+/// or with the codec of the producer.
 pub trait MessageEncodable {
     /// Encode the value into a bytes array to be inserted into Redis.
     ///
     /// In the default implementation, the object is encoded with Msgpack.
-    fn encode_message(&self) -> Result<Vec<u8>, &'static str>;
+    fn encode_message(&self) -> Result<Vec<u8>, Error>;
+
+    /// Encode the value with the given codec.
+    ///
+    /// The default implementation only supports Msgpack, through
+    /// `encode_message`.
+    fn encode_message_with<C: Codec>(&self, codec: &C) -> Result<Vec<u8>, Error> {
+        if codec.tag() != MsgPackCodec.tag() {
+            return Err(Error::Encode(
+                format!("unsupported codec {:?}", codec).into(),
+            ));
+        }
+        self.encode_message()
+    }
 }
 
 impl<T: DeserializeOwned> MessageDecodable for T {
-    fn decode_message(value: &Value) -> Result<T, &'static str> {
+    fn decode_message(value: &Value) -> Result<T, Error> {
+        T::decode_message_with(value, &MsgPackCodec)
+    }
+
+    fn decode_message_with<C: Codec>(
+        value: &Value,
+        codec: &C,
+    ) -> Result<T, Error> {
         match *value {
-            Value::Data(ref v) => rmp_serde::decode::from_slice(v)
-                .or(Err("failed to decode value with msgpack")),
-            _ => Err("can only decode from a string"),
+            Value::Data(ref v) => codec.decode(v),
+            _ => Err(Error::UnexpectedReply(value.clone())),
         }
     }
 }
 
 impl<T: Serialize> MessageEncodable for T {
-    fn encode_message(&self) -> Result<Vec<u8>, &'static str> {
-        rmp_serde::encode::to_vec(self).or(Err("failed to encode value"))
+    fn encode_message(&self) -> Result<Vec<u8>, Error> {
+        self.encode_message_with(&MsgPackCodec)
+    }
+
+    fn encode_message_with<C: Codec>(&self, codec: &C) -> Result<Vec<u8>, Error> {
+        codec.encode(self)
     }
 }
 
-pub struct MessageGuard<'a, T: 'a> {
+pub struct MessageGuard<'a, T: 'a, C: Codec = MsgPackCodec> {
     message: T,
     payload: Vec<u8>,
-    client: &'a RefCell<redis::Connection>,
-    processing_queue_name: String,
-    unacked_queue_name: String,
+    envelope: Option<Envelope>,
+    source_queue: &'a str,
+    consumer: &'a Consumer<C>,
     state: MessageState,
 }
 
-impl<'a, T> MessageGuard<'a, T> {
+impl<'a, T, C: Codec> MessageGuard<'a, T, C> {
     pub fn new(
         message: T,
         payload: Vec<u8>,
-        client: &'a RefCell<redis::Connection>,
-        processing_queue_name: String,
-        unacked_queue_name: String,
-    ) -> MessageGuard<'a, T> {
+        envelope: Option<Envelope>,
+        consumer: &'a Consumer<C>,
+    ) -> MessageGuard<'a, T, C> {
         MessageGuard {
             message,
             payload,
-            client,
-            processing_queue_name,
-            unacked_queue_name,
+            envelope,
+            source_queue: consumer.source_queue(),
+            consumer,
             state: MessageState::Unacked,
         }
     }
 
+    /// Set the source queue the message was fetched from, if it is not the
+    /// main source queue of the consumer.
+    pub(crate) fn set_source_queue(&mut self, source_queue: &'a str) {
+        self.source_queue = source_queue;
+    }
+
+    /// Get the name of the source queue the message was fetched from.
+    pub fn source_queue(&self) -> &str {
+        self.source_queue
+    }
+
     pub fn payload(&self) -> &[u8] {
         &self.payload
     }
 
+    /// Get the envelope of the message, if it was pushed with one.
+    pub fn envelope(&self) -> Option<&Envelope> {
+        self.envelope.as_ref()
+    }
+
+    /// Get the unique id of the message, if it was pushed with an envelope.
+    pub fn id(&self) -> Option<&str> {
+        self.envelope.as_ref().map(Envelope::id)
+    }
+
+    /// Get the time the message was first pushed, in milliseconds since the
+    /// Unix epoch, if it was pushed with an envelope.
+    pub fn created_at(&self) -> Option<u64> {
+        self.envelope.as_ref().map(Envelope::created_at)
+    }
+
+    /// Get the number of failed delivery attempts of the message.
+    pub fn attempts(&self) -> u32 {
+        self.envelope.as_ref().map_or(0, Envelope::attempts)
+    }
+
+    /// Get the value of a header of the message.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.envelope.as_ref().and_then(|e| e.header(name))
+    }
+
     pub fn message(&self) -> &T {
         &self.message
     }
 
     /// Acknowledge the message and remove it from the *processing* queue.
-    pub fn ack(&mut self) -> RedisResult<Value> {
+    pub fn ack(&mut self) -> Result<Value, Error> {
         self.state = MessageState::Acked;
-        self.client.borrow_mut().lrem(
-            self.processing_queue_name.as_str(),
-            1,
-            self.payload.clone(),
-        )
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .cmd("LREM")
+            .arg(self.consumer.processing_queue())
+            .arg(1)
+            .arg(self.payload.clone())
+            .cmd("HDEL")
+            .arg(self.consumer.origins_key())
+            .arg(self.payload.clone())
+            .ignore();
+        self.clear_deadline(&mut pipe);
+        let (removed,): (Value,) = pipe.query(&mut *lock(self.client()))?;
+        Ok(removed)
+    }
+
+    /// Push the visibility deadline of the message `duration` from now, so
+    /// that the garbage collector does not requeue it while it is still being
+    /// processed. It has no effect if the message has no deadline, i.e. the
+    /// consumer has no visibility timeout.
+    ///
+    /// Returns `false` if the message had no deadline, e.g. because it was
+    /// already requeued.
+    pub fn extend(&self, duration: Duration) -> Result<bool, Error> {
+        let deadline = now_millis() + duration.as_millis() as u64;
+        let n: u64 = redis::cmd("ZADD")
+            .arg(self.consumer.deadlines_key())
+            .arg("XX")
+            .arg("CH")
+            .arg(deadline)
+            .arg(self.deadline_member())
+            .query(&mut *lock(self.client()))?;
+        Ok(n == 1)
     }
 
     /// Reject the message and push it from the *processing* queue to the
     /// *unack* queue.
-    pub fn reject(&mut self) -> RedisResult<Value> {
+    pub fn reject(&mut self) -> Result<Value, Error> {
         self.state = MessageState::Rejected;
-        self.push(self.unacked_queue_name.clone())
+        self.move_to(self.consumer.unacked_queue(), false)
+    }
+
+    /// Reject the message like `reject()` and record the reason alongside the
+    /// payload, so that it can be retrieved with `Consumer::rejection_reason()`.
+    pub fn reject_with_reason(&mut self, reason: &str) -> Result<Value, Error> {
+        self.state = MessageState::Rejected;
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .cmd("LPUSH")
+            .arg(self.consumer.unacked_queue())
+            .arg(self.payload.clone())
+            .ignore()
+            .cmd("LREM")
+            .arg(self.consumer.processing_queue())
+            .arg(1)
+            .arg(self.payload.clone())
+            .ignore()
+            .cmd("HSET")
+            .arg(self.consumer.rejections_key())
+            .arg(self.payload.clone())
+            .arg(reason)
+            .ignore();
+        self.clear_deadline(&mut pipe);
+        Ok(pipe.query(&mut *lock(self.client()))?)
     }
 
     /// Remove the message from the processing queue and push it to the
     /// specified queue. It can be used to implement retries.
-    pub fn push(&mut self, push_queue_name: String) -> RedisResult<Value> {
+    pub fn push(&mut self, push_queue_name: String) -> Result<Value, Error> {
         self.state = MessageState::Pushed;
-        redis::pipe()
-            .atomic()
+        self.move_to(&push_queue_name, true)
+    }
+
+    /// Move the message from the *processing* queue to the given queue. Its
+    /// origin is kept while it stays with the consumer, in the *unack* queue.
+    fn move_to(&self, queue: &str, forget_origin: bool) -> Result<Value, Error> {
+        let mut pipe = redis::pipe();
+        pipe.atomic()
             .cmd("LPUSH")
-            .arg(push_queue_name.as_str())
+            .arg(queue)
+            .arg(self.payload.clone())
+            .ignore()
+            .cmd("LREM")
+            .arg(self.consumer.processing_queue())
+            .arg(1)
             .arg(self.payload.clone())
+            .ignore();
+        self.clear_deadline(&mut pipe);
+        if forget_origin {
+            pipe.cmd("HDEL")
+                .arg(self.consumer.origins_key())
+                .arg(self.payload.clone())
+                .ignore();
+        }
+        Ok(pipe.query(&mut *lock(self.client()))?)
+    }
+
+    /// Remove the message from the *processing* queue and move it to the
+    /// dead-letter queue, recording the reason, the consumer name and the
+    /// time. It can then be inspected or replayed with `DeadLetterQueue`.
+    pub fn dead_letter(&mut self, reason: &str) -> Result<(), Error> {
+        self.state = MessageState::DeadLettered;
+        let entry = DeadLetter::new(
+            self.payload.clone(),
+            self.envelope.as_ref(),
+            reason,
+            self.consumer.name(),
+        );
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .cmd("LPUSH")
+            .arg(self.dead_letter_queue())
+            .arg(entry.encode()?)
             .ignore()
             .cmd("LREM")
-            .arg(self.processing_queue_name.as_str())
+            .arg(self.consumer.processing_queue())
             .arg(1)
             .arg(self.payload.clone())
             .ignore()
-            .query(&mut *self.client.borrow_mut())
+            .cmd("HDEL")
+            .arg(self.consumer.origins_key())
+            .arg(self.payload.clone())
+            .ignore();
+        self.clear_deadline(&mut pipe);
+        Ok(pipe.query(&mut *lock(self.client()))?)
+    }
+
+    /// Schedule the message for another attempt, according to the retry
+    /// policy of the consumer.
+    ///
+    /// The number of attempts in the envelope is incremented and the message
+    /// is moved from the *processing* queue to the delayed queue, to be pushed
+    /// back to the *source* queue once the backoff delay expires. If the
+    /// message exhausted its attempts, it is moved to the dead-letter queue
+    /// instead.
+    pub fn retry(&mut self) -> Result<RetryOutcome, Error> {
+        let policy = self.consumer.retry_policy();
+        let (envelope, body) = Envelope::parse(&self.payload)?;
+        let envelope = match envelope {
+            Some(envelope) => envelope.retried(),
+            None => {
+                let mut envelope = Envelope::new(Default::default());
+                envelope.set_codec(self.consumer.codec());
+                envelope.retried()
+            }
+        };
+        let mut payload = envelope.wrap(body)?;
+        if let Some(signer) = self.consumer.signer() {
+            payload = signer.sign(&payload);
+        }
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        let outcome = if policy.is_exhausted(envelope.attempts()) {
+            self.state = MessageState::DeadLettered;
+            let reason = format!("exhausted {} attempts", envelope.attempts());
+            let entry = DeadLetter::new(
+                payload,
+                Some(&envelope),
+                &reason,
+                self.consumer.name(),
+            );
+            pipe.cmd("LPUSH")
+                .arg(self.dead_letter_queue())
+                .arg(entry.encode()?)
+                .ignore();
+            RetryOutcome::DeadLettered
+        } else {
+            self.state = MessageState::Retried;
+            let delay = policy.delay(envelope.attempts());
+            let due = now_millis() + delay.as_millis() as u64;
+            pipe.cmd("ZADD")
+                .arg(DELAYED_QUEUE_KEY.replace("{queue}", self.source_queue))
+                .arg(due)
+                .arg(payload)
+                .ignore()
+                .cmd("SADD")
+                .arg(DELAYED_QUEUES_KEY)
+                .arg(self.source_queue)
+                .ignore();
+            RetryOutcome::Scheduled(delay)
+        };
+        pipe.cmd("LREM")
+            .arg(self.consumer.processing_queue())
+            .arg(1)
+            .arg(self.payload.clone())
+            .ignore()
+            .cmd("HDEL")
+            .arg(self.consumer.origins_key())
+            .arg(self.payload.clone())
+            .ignore();
+        self.clear_deadline(&mut pipe);
+        let _: () = pipe.query(&mut *lock(self.client()))?;
+        Ok(outcome)
     }
 
-    pub fn client(&self) -> &RefCell<redis::Connection> {
-        self.client
+    /// Get the member of the message in the deadlines of the consumer.
+    fn deadline_member(&self) -> &[u8] {
+        envelope::deadline_member(self.envelope.as_ref(), &self.payload)
+    }
+
+    /// Add the commands removing the deadline of the message to the pipeline.
+    fn clear_deadline(&self, pipe: &mut redis::Pipeline) {
+        self.consumer
+            .clear_deadline(pipe, self.envelope.as_ref(), &self.payload);
+    }
+
+    /// Get the dead-letter queue of the source queue of the message.
+    fn dead_letter_queue(&self) -> String {
+        DEAD_LETTER_QUEUE_KEY.replace("{queue}", self.source_queue)
+    }
+
+    pub fn client(&self) -> &Mutex<redis::Connection> {
+        self.consumer.client()
+    }
+
+    pub fn consumer(&self) -> &Consumer<C> {
+        self.consumer
     }
 }
 
-impl<'a, T> Deref for MessageGuard<'a, T> {
+impl<'a, T, C: Codec> Deref for MessageGuard<'a, T, C> {
     type Target = T;
 
     fn deref(&self) -> &T {
@@ -136,7 +408,7 @@ impl<'a, T> Deref for MessageGuard<'a, T> {
     }
 }
 
-impl<'a, T> Drop for MessageGuard<'a, T> {
+impl<'a, T, C: Codec> Drop for MessageGuard<'a, T, C> {
     fn drop(&mut self) {
         if self.state == MessageState::Unacked {
             let _ = self.reject();
@@ -147,88 +419,87 @@ impl<'a, T> Drop for MessageGuard<'a, T> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use redis::Value;
+    use redis::{Commands, Value};
     use serde::{Deserialize, Serialize};
 
     #[derive(Debug, PartialEq, Serialize, Deserialize)]
     struct BrokenMessage {}
 
+    fn unexpected_reply(value: Value) -> bool {
+        match BrokenMessage::decode_message(&value) {
+            Err(Error::UnexpectedReply(v)) => v == value,
+            _ => false,
+        }
+    }
+
     #[test]
     fn cant_decode_if_not_string() {
-        let err = Err("can only decode from a string");
-        assert_eq!(BrokenMessage::decode_message(&Value::Nil), err);
-        assert_eq!(BrokenMessage::decode_message(&Value::Int(24)), err);
-        assert_eq!(
-            BrokenMessage::decode_message(&Value::Bulk(vec![Value::Nil])),
-            err
-        );
-        assert_eq!(
-            BrokenMessage::decode_message(&Value::Status("info".into())),
-            err
-        );
-        assert_eq!(BrokenMessage::decode_message(&Value::Okay), err);
+        assert!(unexpected_reply(Value::Nil));
+        assert!(unexpected_reply(Value::Int(24)));
+        assert!(unexpected_reply(Value::Bulk(vec![Value::Nil])));
+        assert!(unexpected_reply(Value::Status("info".into())));
+        assert!(unexpected_reply(Value::Okay));
     }
 
     #[test]
     fn cant_decode_if_not_msgpack() {
-        let err = Err("failed to decode value with msgpack");
-        assert_eq!(
-            BrokenMessage::decode_message(&Value::Data(vec![1, 2, 3])),
-            err
-        );
+        let err = BrokenMessage::decode_message(&Value::Data(vec![1, 2, 3]));
+        assert!(err.unwrap_err().is_poison());
+    }
+
+    fn test_consumer(client: &redis::Client) -> Consumer {
+        let con = client.get_connection().unwrap();
+        Consumer::new("guard_test".into(), "q_test".into(), con)
+    }
+
+    fn cleanup(con: &mut redis::Connection, consumer: &Consumer) {
+        let _: () = con.del(consumer.processing_queue()).unwrap();
+        let _: () = con.del(consumer.unacked_queue()).unwrap();
     }
 
     #[test]
     fn payload_field_is_accessible() {
         let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
-        let con = client.get_connection().unwrap();
+        let consumer = test_consumer(&client);
         let mut con2 = client.get_connection().unwrap();
 
         let bm = BrokenMessage {};
         let p = vec![1, 2, 3, 4];
-        let rc = RefCell::new(con);
-        let mg =
-            MessageGuard::new(bm, p, &rc, "proc_test".into(), "unack_test".into());
+        let mg = MessageGuard::new(bm, p, None, &consumer);
 
         assert_eq!(Vec::from(mg.payload()), vec![1, 2, 3, 4]);
 
-        let _: () = con2.del("proc_test").unwrap();
-        let _: () = con2.del("unack_test").unwrap();
+        cleanup(&mut con2, &consumer);
     }
 
     #[test]
     fn message_field_is_accessible() {
         let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
-        let con = client.get_connection().unwrap();
+        let consumer = test_consumer(&client);
         let mut con2 = client.get_connection().unwrap();
 
         let bm = BrokenMessage {};
         let p = vec![1, 2, 3, 4];
-        let rc = RefCell::new(con);
-        let mg =
-            MessageGuard::new(bm, p, &rc, "proc_test".into(), "unack_test".into());
+        let mg = MessageGuard::new(bm, p, None, &consumer);
 
         assert_eq!(*mg.message(), BrokenMessage {});
 
-        let _: () = con2.del("proc_test").unwrap();
-        let _: () = con2.del("unack_test").unwrap();
+        cleanup(&mut con2, &consumer);
     }
 
     #[test]
     fn client_field_is_accessible() {
         let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
-        let con = client.get_connection().unwrap();
+        let consumer = test_consumer(&client);
         let mut con2 = client.get_connection().unwrap();
 
         let bm = BrokenMessage {};
         let p = vec![1, 2, 3, 4];
-        let rc = RefCell::new(con);
-        let mg =
-            MessageGuard::new(bm, p, &rc, "proc_test".into(), "unack_test".into());
+        let mg = MessageGuard::new(bm, p, None, &consumer);
 
-        assert_eq!(mg.client() as *const _, &rc as *const _);
+        assert_eq!(mg.client() as *const _, consumer.client() as *const _);
+        assert_eq!(mg.consumer() as *const _, &consumer as *const _);
 
-        let _: () = con2.del("proc_test").unwrap();
-        let _: () = con2.del("unack_test").unwrap();
+        cleanup(&mut con2, &consumer);
     }
 }
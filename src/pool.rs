@@ -0,0 +1,209 @@
+use crate::consumer::{Consumer, StopHandle};
+use crate::error::Error;
+use crate::message::MessageDecodable;
+use crate::shutdown::{self, SWEEP_SCRIPT};
+use redis::Script;
+use std::fmt;
+use std::panic;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// A pool of consumers processing the same source queue concurrently.
+///
+/// Each consumer runs in its own thread with its own connection. Messages are
+/// acknowledged if the handler returns `Ok` and rejected otherwise. Consumers
+/// whose connection fails are restarted with a fresh connection, after moving
+/// the messages they were processing back to the source queue.
+pub struct WorkerPool {
+    client: redis::Client,
+    source_queue_name: String,
+    concurrency: usize,
+    block_timeout: Duration,
+    restart_delay: Duration,
+    heartbeat: Option<(Duration, Duration)>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl WorkerPool {
+    pub fn new(
+        client: redis::Client,
+        source_queue_name: String,
+        concurrency: usize,
+    ) -> WorkerPool {
+        WorkerPool {
+            client,
+            source_queue_name,
+            concurrency,
+            block_timeout: Duration::from_secs(1),
+            restart_delay: Duration::from_secs(1),
+            heartbeat: None,
+            stopped: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Set the block timeout of the consumers, i.e. the maximum time it takes
+    /// them to observe a stop request. Defaults to one second.
+    pub fn set_block_timeout(&mut self, timeout: Duration) {
+        self.block_timeout = timeout;
+    }
+
+    /// Set how long to wait before restarting a consumer whose connection
+    /// failed. Defaults to one second.
+    pub fn set_restart_delay(&mut self, delay: Duration) {
+        self.restart_delay = delay;
+    }
+
+    /// Make every consumer send heartbeats with the given TTL every
+    /// `interval`, from a background thread with its own connection. See
+    /// `Consumer::start_heartbeat()`.
+    pub fn set_heartbeat(&mut self, interval: Duration, ttl: Duration) {
+        self.heartbeat = Some((interval, ttl));
+    }
+
+    /// Get the number of consumers.
+    pub fn concurrency(&self) -> usize {
+        self.concurrency
+    }
+
+    /// Get the source queue name.
+    pub fn source_queue(&self) -> &str {
+        &self.source_queue_name
+    }
+
+    /// Get a handle that stops all the consumers of the pool.
+    pub fn stop_handle(&self) -> StopHandle {
+        StopHandle::new(self.stopped.clone())
+    }
+
+    /// Stop all the consumers of the pool.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    /// Check if the pool is stopped.
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Get the name of the consumer with the given index, which is unique
+    /// across hosts and processes.
+    pub fn consumer_name(&self, index: usize) -> String {
+        let host = hostname::get()
+            .map(|h| h.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| "localhost".into());
+        format!(
+            "{}:{}:{}:{}",
+            host,
+            process::id(),
+            self.source_queue_name,
+            index
+        )
+    }
+
+    /// Run the handler on every message of the source queue until the pool is
+    /// stopped.
+    ///
+    /// This method blocks until all the consumers have exited. Panics in the
+    /// handler are caught and the message rejected, as in `Consumer::run()`.
+    pub fn run<T, E, F>(&self, handler: F)
+    where
+        T: MessageDecodable + 'static,
+        E: fmt::Display + 'static,
+        F: Fn(&T) -> Result<(), E> + Send + Sync + 'static,
+    {
+        let handler = Arc::new(handler);
+        let handles: Vec<_> = (0..self.concurrency)
+            .map(|i| {
+                let worker = Worker {
+                    client: self.client.clone(),
+                    name: self.consumer_name(i),
+                    source_queue_name: self.source_queue_name.clone(),
+                    block_timeout: self.block_timeout,
+                    restart_delay: self.restart_delay,
+                    heartbeat: self.heartbeat,
+                    stop_handle: self.stop_handle(),
+                    sweep_script: Script::new(SWEEP_SCRIPT),
+                };
+                let handler = handler.clone();
+                thread::spawn(move || worker.supervise(&*handler))
+            })
+            .collect();
+
+        let mut panicked = None;
+        for handle in handles {
+            if let Err(e) = handle.join() {
+                self.stop();
+                panicked = Some(e);
+            }
+        }
+        // Handler panics are caught by the consumers, so this is a bug in the
+        // pool itself.
+        if let Some(e) = panicked {
+            panic::resume_unwind(e);
+        }
+    }
+}
+
+struct Worker {
+    client: redis::Client,
+    name: String,
+    source_queue_name: String,
+    block_timeout: Duration,
+    restart_delay: Duration,
+    heartbeat: Option<(Duration, Duration)>,
+    stop_handle: StopHandle,
+    sweep_script: Script,
+}
+
+impl Worker {
+    fn supervise<T, E, F>(&self, handler: &F)
+    where
+        T: MessageDecodable,
+        E: fmt::Display,
+        F: Fn(&T) -> Result<(), E>,
+    {
+        while !self.stop_handle.is_stopped() {
+            // Any Redis error is treated as a dropped connection: the consumer
+            // is restarted with a new one.
+            if self.consume(handler).is_err() && !self.stop_handle.is_stopped() {
+                thread::sleep(self.restart_delay);
+            }
+        }
+    }
+
+    fn consume<T, E, F>(&self, handler: &F) -> Result<(), Error>
+    where
+        T: MessageDecodable,
+        E: fmt::Display,
+        F: Fn(&T) -> Result<(), E>,
+    {
+        let con = self.client.get_connection()?;
+        let mut consumer =
+            Consumer::new(self.name.clone(), self.source_queue_name.clone(), con);
+        consumer.set_fetch_connection(self.client.get_connection()?);
+        consumer.set_block_timeout(self.block_timeout);
+        consumer.set_stop_handle(self.stop_handle.clone());
+        // The messages being processed when the connection of the previous
+        // consumer failed are still in its processing queue, and the new one
+        // has the same name: they are requeued before fetching new ones.
+        shutdown::sweep(
+            &self.sweep_script,
+            &consumer,
+            consumer.source_queue(),
+            1,
+        )?;
+        consumer.register()?;
+        if let Some((interval, ttl)) = self.heartbeat {
+            let con = self.client.get_connection()?;
+            consumer.start_heartbeat(con, interval, ttl);
+        }
+
+        consumer.run(|message: &T| handler(message))?;
+        consumer.stop_heartbeat();
+        consumer.deregister()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,239 @@
+use crate::codec::{Codec, MsgPackCodec};
+use crate::envelope::{self, EncodeOptions};
+use crate::error::Error;
+use crate::message;
+use rand::Rng;
+use redis::{Commands, RedisResult, Script, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+// Move the last message of the first non-empty source queue to the processing
+// queue, which is the second to last key. If the source queue has a name in
+// ARGV, record it as the origin of the message in the hash that is the last
+// key. Returns the zero-based index of the source queue and the message, or nil
+// if all the source queues are empty.
+pub(crate) const FETCH_SCRIPT: &str = r"
+local n = #KEYS - 2
+for i = 1, n do
+    local payload = redis.call('RPOPLPUSH', KEYS[i], KEYS[n + 1])
+    if payload then
+        if ARGV[i] then
+            redis.call('HSET', KEYS[n + 2], payload, ARGV[i])
+        end
+        return {i - 1, payload}
+    end
+end
+return nil
+";
+
+/// The priority of a message.
+///
+/// Messages with normal priority are pushed to the source queue itself, so
+/// that plain producers, retries and the garbage collector keep working with
+/// priority queues. High and low priority messages have their own lists.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Priority {
+    High,
+    Normal,
+    Low,
+}
+
+impl Priority {
+    /// All the priorities, from the highest to the lowest.
+    pub const ALL: [Priority; 3] =
+        [Priority::High, Priority::Normal, Priority::Low];
+
+    /// Get the name of the list holding the messages of the given source queue
+    /// with this priority.
+    pub fn queue_name(self, source_queue_name: &str) -> String {
+        match self {
+            Priority::High => format!("{}:high", source_queue_name),
+            Priority::Normal => source_queue_name.to_string(),
+            Priority::Low => format!("{}:low", source_queue_name),
+        }
+    }
+}
+
+/// How a consumer picks the list to fetch the next message from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PriorityMode {
+    /// Only fetch from the source queue, ignoring the priority lists.
+    Disabled,
+    /// Always fetch the message with the highest priority.
+    Strict,
+    /// Pick the priority to fetch first at random, in proportion to the given
+    /// weights, so that lower priorities are not starved. If the picked list is
+    /// empty, the others are tried from the highest priority.
+    Weighted { high: u32, normal: u32, low: u32 },
+}
+
+impl PriorityMode {
+    /// Get the priority lists of the given source queue, in the order in which
+    /// they should be tried.
+    pub(crate) fn queue_names(self, source_queue_name: &str) -> Vec<String> {
+        let order = match self {
+            PriorityMode::Disabled => vec![Priority::Normal],
+            PriorityMode::Strict => Priority::ALL.to_vec(),
+            PriorityMode::Weighted { high, normal, low } => {
+                let mut order = Priority::ALL.to_vec();
+                let weights = [high, normal, low];
+                let total: u32 = weights.iter().sum();
+                if total > 0 {
+                    let mut pick = rand::thread_rng().gen_range(0, total);
+                    for (i, &weight) in weights.iter().enumerate() {
+                        if pick < weight {
+                            let first = order.remove(i);
+                            order.insert(0, first);
+                            break;
+                        }
+                        pick -= weight;
+                    }
+                }
+                order
+            }
+        };
+        order
+            .into_iter()
+            .map(|p| p.queue_name(source_queue_name))
+            .collect()
+    }
+}
+
+/// Atomically move the next message from the first non-empty source queue to
+/// the processing queue, without blocking.
+///
+/// The script is `FETCH_SCRIPT`, which callers keep so that its hash is not
+/// computed again on every poll.
+///
+/// If `origins` is given, the name of the source queue each list belongs to is
+/// recorded in the origins hash, so that the message can be requeued there.
+///
+/// Returns the index of the source queue and the message, if any.
+pub(crate) fn fetch(
+    con: &mut dyn redis::ConnectionLike,
+    script: &Script,
+    source_queues: &[String],
+    processing_queue: &str,
+    origins_key: &str,
+    origins: Option<&[&str]>,
+) -> RedisResult<Option<(usize, Value)>> {
+    let mut invocation = script.prepare_invoke();
+    for queue in source_queues {
+        invocation.key(queue.as_str());
+    }
+    for origin in origins.unwrap_or_default() {
+        invocation.arg(*origin);
+    }
+    invocation
+        .key(processing_queue)
+        .key(origins_key)
+        .invoke(con)
+}
+
+/// A producer that pushes messages to the priority lists of a source queue.
+pub struct PriorityProducer<C: Codec = MsgPackCodec> {
+    queue_name: String,
+    options: EncodeOptions<C>,
+    client: RefCell<redis::Connection>,
+}
+
+impl PriorityProducer {
+    pub fn new(queue_name: String, client: redis::Connection) -> PriorityProducer {
+        PriorityProducer {
+            queue_name,
+            options: EncodeOptions::new(),
+            client: RefCell::new(client),
+        }
+    }
+}
+
+impl<C: Codec> PriorityProducer<C> {
+    /// Turn this producer into one encoding jobs with the given codec. Defaults
+    /// to `MsgPackCodec`.
+    pub fn with_codec<D: Codec>(self, codec: D) -> PriorityProducer<D> {
+        PriorityProducer {
+            queue_name: self.queue_name,
+            options: self.options.with_codec(codec),
+            client: self.client,
+        }
+    }
+
+    /// Get the options jobs are encoded with.
+    pub fn options(&self) -> &EncodeOptions<C> {
+        &self.options
+    }
+
+    /// Get a mutable reference to the options jobs are encoded with, to set
+    /// their compression, keyring or signer.
+    pub fn options_mut(&mut self) -> &mut EncodeOptions<C> {
+        &mut self.options
+    }
+
+    /// Push a new job with the given priority.
+    pub fn push<T: message::MessageEncodable>(
+        &self,
+        job: T,
+        priority: Priority,
+    ) -> Result<(), Error> {
+        self.push_with_headers(job, priority, HashMap::new())
+    }
+
+    /// Push a new job with the given priority and headers in its envelope.
+    pub fn push_with_headers<T: message::MessageEncodable>(
+        &self,
+        job: T,
+        priority: Priority,
+        headers: HashMap<String, String>,
+    ) -> Result<(), Error> {
+        let encoded = envelope::encode_payload(&job, headers, &self.options)?;
+        Ok(self
+            .client
+            .borrow_mut()
+            .lpush(priority.queue_name(&self.queue_name), encoded)?)
+    }
+
+    /// Get the number of remaining jobs with the given priority.
+    pub fn size(&self, priority: Priority) -> u64 {
+        self.client
+            .borrow_mut()
+            .llen(priority.queue_name(&self.queue_name))
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normal_priority_is_the_source_queue() {
+        assert_eq!("q", Priority::Normal.queue_name("q"));
+        assert_eq!("q:high", Priority::High.queue_name("q"));
+        assert_eq!("q:low", Priority::Low.queue_name("q"));
+    }
+
+    #[test]
+    fn strict_mode_tries_highest_first() {
+        assert_eq!(
+            vec!["q:high", "q", "q:low"],
+            PriorityMode::Strict.queue_names("q")
+        );
+        assert_eq!(vec!["q"], PriorityMode::Disabled.queue_names("q"));
+    }
+
+    #[test]
+    fn weighted_mode_picks_by_weight() {
+        let mode = PriorityMode::Weighted {
+            high: 0,
+            normal: 0,
+            low: 1,
+        };
+        assert_eq!(vec!["q:low", "q:high", "q"], mode.queue_names("q"));
+        let mode = PriorityMode::Weighted {
+            high: 0,
+            normal: 0,
+            low: 0,
+        };
+        assert_eq!(vec!["q:high", "q", "q:low"], mode.queue_names("q"));
+    }
+}
@@ -1,9 +1,16 @@
+use crate::codec::{Codec, MsgPackCodec};
+use crate::consumer::{DELAYED_QUEUES_KEY, DELAYED_QUEUE_KEY};
+use crate::envelope::{self, EncodeOptions};
+use crate::error::Error;
 use crate::message;
 use redis::Commands;
 use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-pub struct Producer {
+pub struct Producer<C: Codec = MsgPackCodec> {
     queue_name: String,
+    options: EncodeOptions<C>,
     client: RefCell<redis::Connection>,
 }
 
@@ -11,20 +18,94 @@ impl Producer {
     pub fn new(queue_name: String, client: redis::Connection) -> Producer {
         Producer {
             queue_name,
+            options: EncodeOptions::new(),
             client: RefCell::new(client),
         }
     }
+}
+
+impl<C: Codec> Producer<C> {
+    /// Turn this producer into one encoding jobs with the given codec. Defaults
+    /// to `MsgPackCodec`.
+    pub fn with_codec<D: Codec>(self, codec: D) -> Producer<D> {
+        Producer {
+            queue_name: self.queue_name,
+            options: self.options.with_codec(codec),
+            client: self.client,
+        }
+    }
+
+    /// Get the options jobs are encoded with.
+    pub fn options(&self) -> &EncodeOptions<C> {
+        &self.options
+    }
+
+    /// Get a mutable reference to the options jobs are encoded with, to set
+    /// their compression, keyring or signer.
+    pub fn options_mut(&mut self) -> &mut EncodeOptions<C> {
+        &mut self.options
+    }
 
     /// Push a new job to the source queue.
-    pub fn push<T: message::MessageEncodable>(
+    ///
+    /// The job is wrapped in an envelope that records its id, creation time
+    /// and delivery attempts.
+    pub fn push<T: message::MessageEncodable>(&self, job: T) -> Result<(), Error> {
+        self.push_with_headers(job, HashMap::new())
+    }
+
+    /// Push a new job to the source queue, with the given headers in its
+    /// envelope.
+    pub fn push_with_headers<T: message::MessageEncodable>(
         &self,
         job: T,
-    ) -> Result<(), &'static str> {
-        let encoded = job.encode_message()?;
-        self.client
+        headers: HashMap<String, String>,
+    ) -> Result<(), Error> {
+        let encoded = envelope::encode_payload(&job, headers, &self.options)?;
+        Ok(self
+            .client
             .borrow_mut()
-            .lpush(self.queue_name.as_str(), encoded)
-            .or(Err("failed to push"))
+            .lpush(self.queue_name.as_str(), encoded)?)
+    }
+
+    /// Push a new job to the delayed queue, to be moved to the source queue at
+    /// the given time.
+    ///
+    /// Due jobs are moved by the consumers of the queue before fetching, or by
+    /// a `Scheduler`. Jobs due in the past are delivered as soon as possible.
+    pub fn push_at<T: message::MessageEncodable>(
+        &self,
+        job: T,
+        when: SystemTime,
+    ) -> Result<(), Error> {
+        let due = match when.duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_millis() as u64,
+            Err(_) => 0,
+        };
+        let encoded =
+            envelope::encode_payload(&job, HashMap::new(), &self.options)?;
+        Ok(redis::pipe()
+            .atomic()
+            .cmd("ZADD")
+            .arg(DELAYED_QUEUE_KEY.replace("{queue}", self.queue_name.as_str()))
+            .arg(due)
+            .arg(encoded)
+            .ignore()
+            .cmd("SADD")
+            .arg(DELAYED_QUEUES_KEY)
+            .arg(self.queue_name.as_str())
+            .ignore()
+            .query(&mut *self.client.borrow_mut())?)
+    }
+
+    /// Push a new job to the delayed queue, to be moved to the source queue
+    /// once the given delay expires.
+    pub fn push_in<T: message::MessageEncodable>(
+        &self,
+        job: T,
+        delay: Duration,
+    ) -> Result<(), Error> {
+        self.push_at(job, SystemTime::now() + delay)
     }
 
     /// Get the number of remaining jobs in the queue.
@@ -0,0 +1,131 @@
+use crate::codec::{Codec, ProtobufCodec};
+use crate::error::Error;
+use crate::message::{MessageDecodable, MessageEncodable};
+use redis::Value;
+use std::ops::{Deref, DerefMut};
+
+/// A protobuf message generated by prost.
+///
+/// prost messages cannot implement `MessageEncodable` and `MessageDecodable`
+/// directly, because they would collide with the implementations for serde
+/// types, so they are pushed and consumed through this wrapper:
+///
+/// ```ignore
+/// let producer = producer.with_codec(ProtobufCodec);
+/// producer.push(Protobuf(job))?;
+/// let message = consumer.next::<Protobuf<Job>>();
+/// ```
+///
+/// Protobuf messages can only be encoded with `ProtobufCodec`, whose tag is
+/// recorded in their envelope.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Protobuf<T>(pub T);
+
+impl<T> Protobuf<T> {
+    /// Get the wrapped message.
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> From<T> for Protobuf<T> {
+    fn from(message: T) -> Protobuf<T> {
+        Protobuf(message)
+    }
+}
+
+impl<T> Deref for Protobuf<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Protobuf<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: prost::Message> MessageEncodable for Protobuf<T> {
+    fn encode_message(&self) -> Result<Vec<u8>, Error> {
+        Ok(self.0.encode_to_vec())
+    }
+
+    fn encode_message_with<C: Codec>(&self, codec: &C) -> Result<Vec<u8>, Error> {
+        if codec.tag() != ProtobufCodec.tag() {
+            return Err(Error::Encode(
+                format!("protobuf messages cannot be encoded with {:?}", codec)
+                    .into(),
+            ));
+        }
+        self.encode_message()
+    }
+}
+
+impl<T: prost::Message + Default> MessageDecodable for Protobuf<T> {
+    fn decode_message(value: &Value) -> Result<Protobuf<T>, Error> {
+        match *value {
+            Value::Data(ref v) => T::decode(v.as_slice())
+                .map(Protobuf)
+                .map_err(|e| Error::Decode(Box::new(e))),
+            _ => Err(Error::UnexpectedReply(value.clone())),
+        }
+    }
+
+    fn decode_message_with<C: Codec>(
+        value: &Value,
+        codec: &C,
+    ) -> Result<Protobuf<T>, Error> {
+        if codec.tag() != ProtobufCodec.tag() {
+            return Err(Error::Decode(
+                format!("protobuf messages cannot be decoded with {:?}", codec)
+                    .into(),
+            ));
+        }
+        Self::decode_message(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::MsgPackCodec;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct Job {
+        #[prost(uint64, tag = "1")]
+        id: u64,
+        #[prost(string, tag = "2")]
+        name: String,
+    }
+
+    #[test]
+    fn encodes_and_decodes() {
+        let job = Protobuf(Job {
+            id: 42,
+            name: "answer".into(),
+        });
+        let encoded = job.encode_message_with(&ProtobufCodec).unwrap();
+        let decoded: Protobuf<Job> = MessageDecodable::decode_message_with(
+            &Value::Data(encoded),
+            &ProtobufCodec,
+        )
+        .unwrap();
+        assert_eq!(job, decoded);
+    }
+
+    #[test]
+    fn other_codecs_are_rejected() {
+        let job = Protobuf(Job::default());
+        assert!(job.encode_message_with(&MsgPackCodec).is_err());
+        let err = Protobuf::<Job>::decode_message_with(
+            &Value::Data(vec![]),
+            &MsgPackCodec,
+        )
+        .unwrap_err();
+        assert!(err.is_poison());
+        assert!(ProtobufCodec.encode(&42u64).is_err());
+    }
+}
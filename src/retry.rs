@@ -0,0 +1,135 @@
+use std::time::Duration;
+
+/// Exponential backoff policy used by `MessageGuard::retry()`.
+///
+/// The n-th retry is delayed by `base_delay * 2^(n - 1)`, capped at
+/// `max_delay`. With a non-zero jitter, the delay is randomly reduced by up to
+/// the given fraction of it, so that messages that failed together are not
+/// retried together. Once a message has failed `max_attempts` times, it is
+/// moved to the dead-letter queue instead.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    jitter: f64,
+}
+
+/// The outcome of `MessageGuard::retry()`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RetryOutcome {
+    /// The message will be pushed back to the source queue after the delay.
+    Scheduled(Duration),
+    /// The message exhausted its attempts and was moved to the dead-letter
+    /// queue.
+    DeadLettered,
+}
+
+impl RetryPolicy {
+    pub fn new(max_attempts: u32, base_delay: Duration) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            base_delay,
+            max_delay: Duration::from_secs(3600),
+            jitter: 0.0,
+        }
+    }
+
+    /// Set the maximum delay between two attempts. Defaults to one hour.
+    pub fn set_max_delay(&mut self, max_delay: Duration) {
+        self.max_delay = max_delay;
+    }
+
+    /// Set the fraction of the delay that is randomized, between 0 and 1.
+    /// Defaults to 0.
+    pub fn set_jitter(&mut self, jitter: f64) {
+        self.jitter = jitter.clamp(0.0, 1.0);
+    }
+
+    /// Get the maximum number of attempts.
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Get the delay of the first retry.
+    pub fn base_delay(&self) -> Duration {
+        self.base_delay
+    }
+
+    /// Get the maximum delay between two attempts.
+    pub fn max_delay(&self) -> Duration {
+        self.max_delay
+    }
+
+    /// Get the fraction of the delay that is randomized.
+    pub fn jitter(&self) -> f64 {
+        self.jitter
+    }
+
+    /// Check if a message that failed the given number of times must be
+    /// dead-lettered.
+    pub fn is_exhausted(&self, attempts: u32) -> bool {
+        attempts >= self.max_attempts
+    }
+
+    /// Compute the delay before retrying a message that failed the given
+    /// number of times.
+    pub fn delay(&self, attempts: u32) -> Duration {
+        let exp = attempts.saturating_sub(1).min(31);
+        let delay = self
+            .base_delay
+            .checked_mul(1 << exp)
+            .map_or(self.max_delay, |d| d.min(self.max_delay));
+        if self.jitter > 0.0 {
+            delay.mul_f64(1.0 - self.jitter * rand::random::<f64>())
+        } else {
+            delay
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    /// Five attempts, starting with a one second delay.
+    fn default() -> RetryPolicy {
+        RetryPolicy::new(5, Duration::from_secs(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_grows_exponentially() {
+        let policy = RetryPolicy::new(10, Duration::from_millis(100));
+        assert_eq!(Duration::from_millis(100), policy.delay(1));
+        assert_eq!(Duration::from_millis(200), policy.delay(2));
+        assert_eq!(Duration::from_millis(800), policy.delay(4));
+    }
+
+    #[test]
+    fn delay_is_capped() {
+        let mut policy = RetryPolicy::new(10, Duration::from_secs(1));
+        policy.set_max_delay(Duration::from_secs(5));
+        assert_eq!(Duration::from_secs(5), policy.delay(4));
+        assert_eq!(Duration::from_secs(5), policy.delay(u32::MAX));
+    }
+
+    #[test]
+    fn jitter_reduces_delay() {
+        let mut policy = RetryPolicy::new(10, Duration::from_secs(1));
+        policy.set_jitter(0.5);
+        for _ in 0..100 {
+            let delay = policy.delay(1);
+            assert!(delay <= Duration::from_secs(1));
+            assert!(delay >= Duration::from_millis(500));
+        }
+    }
+
+    #[test]
+    fn exhausted_after_max_attempts() {
+        let policy = RetryPolicy::new(3, Duration::from_secs(1));
+        assert!(!policy.is_exhausted(2));
+        assert!(policy.is_exhausted(3));
+    }
+}
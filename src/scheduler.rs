@@ -0,0 +1,60 @@
+use crate::consumer::{now_millis, DELAYED_QUEUES_KEY, DELAYED_QUEUE_KEY};
+use crate::delayed;
+use crate::error::Error;
+use redis::{Commands, Script};
+use std::cell::RefCell;
+
+/// Maximum number of due messages moved by each script invocation.
+const BATCH_SIZE: u64 = 100;
+
+/// Moves the due messages of the delayed queues to their source queues.
+///
+/// Like the garbage collector, the scheduler is designed to be called
+/// periodically. Consumers also move due messages before fetching, so it is
+/// only needed for queues that can be idle for a while.
+pub struct Scheduler {
+    promote_script: Script,
+    client: RefCell<redis::Connection>,
+}
+
+impl Scheduler {
+    pub fn new(client: redis::Connection) -> Scheduler {
+        Scheduler {
+            promote_script: Script::new(delayed::PROMOTE_SCRIPT),
+            client: RefCell::new(client),
+        }
+    }
+
+    /// Move the due messages of the given source queue.
+    ///
+    /// Returns the number of moved messages.
+    pub fn schedule_one(&self, source_queue_name: &str) -> Result<u64, Error> {
+        let delayed_key = DELAYED_QUEUE_KEY.replace("{queue}", source_queue_name);
+        let mut total: u64 = 0;
+        loop {
+            let (moved, _) = delayed::promote(
+                &mut *self.client.borrow_mut(),
+                &self.promote_script,
+                &delayed_key,
+                source_queue_name,
+                now_millis(),
+                BATCH_SIZE,
+            )?;
+            total += moved;
+            if moved < BATCH_SIZE {
+                return Ok(total);
+            }
+        }
+    }
+
+    /// Move the due messages of all the queues that have delayed messages.
+    pub fn schedule(&self) -> Result<u64, Error> {
+        let vals: Vec<String> =
+            self.client.borrow_mut().smembers(DELAYED_QUEUES_KEY)?;
+        let mut total: u64 = 0;
+        for name in vals {
+            total += self.schedule_one(name.as_str()).unwrap_or(0);
+        }
+        Ok(total)
+    }
+}
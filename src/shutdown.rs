@@ -0,0 +1,169 @@
+use crate::codec::Codec;
+use crate::consumer::{lock, Consumer};
+use crate::error::Error;
+use redis::{Commands, Script};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Maximum number of messages moved by each invocation of the sweep script.
+const SWEEP_BATCH_SIZE: u64 = 100;
+
+// Move up to ARGV[1] messages from the processing queue KEYS[1] to KEYS[2], or
+// if ARGV[2] is 1 back to the source queue recorded in the origins hash
+// KEYS[3], defaulting to KEYS[2]. Returns the number of moved messages.
+pub(crate) const SWEEP_SCRIPT: &str = r"
+local limit = tonumber(ARGV[1])
+local n = 0
+while n < limit do
+    local payload = redis.call('RPOP', KEYS[1])
+    if not payload then
+        break
+    end
+    local destination = KEYS[2]
+    if ARGV[2] == '1' then
+        destination = redis.call('HGET', KEYS[3], payload) or destination
+        redis.call('HDEL', KEYS[3], payload)
+    end
+    redis.call('LPUSH', destination, payload)
+    n = n + 1
+end
+return n
+";
+
+/// What to do with the messages that are still being processed when the
+/// grace period expires.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShutdownAction {
+    /// Move them to the *unack* queue, as if they had been rejected.
+    Reject,
+    /// Move them back to the *source* queue they were fetched from, so that
+    /// other consumers can process them straight away.
+    Requeue,
+}
+
+/// Coordinates the graceful shutdown of a consumer.
+///
+/// The consumer is stopped, then outstanding messages are given a grace
+/// period to be acknowledged. The messages still in the *processing* queue
+/// afterwards are rejected or requeued, and the consumer is deregistered.
+pub struct Shutdown {
+    grace_period: Duration,
+    action: ShutdownAction,
+    sweep_script: Script,
+}
+
+impl Shutdown {
+    pub fn new(grace_period: Duration) -> Shutdown {
+        Shutdown {
+            grace_period,
+            action: ShutdownAction::Requeue,
+            sweep_script: Script::new(SWEEP_SCRIPT),
+        }
+    }
+
+    /// Set what to do with the messages left after the grace period. Defaults
+    /// to `ShutdownAction::Requeue`.
+    pub fn set_action(&mut self, action: ShutdownAction) {
+        self.action = action;
+    }
+
+    /// Get the grace period.
+    pub fn grace_period(&self) -> Duration {
+        self.grace_period
+    }
+
+    /// Get the action applied to the messages left after the grace period.
+    pub fn action(&self) -> ShutdownAction {
+        self.action
+    }
+
+    /// Block until the process receives SIGINT or SIGTERM, then drain the
+    /// consumer.
+    #[cfg(feature = "signals")]
+    pub fn run<C: Codec>(&self, consumer: &Consumer<C>) -> Result<u64, Error> {
+        use signal_hook::consts::{SIGINT, SIGTERM};
+        use signal_hook::iterator::Signals;
+
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        signals.forever().next();
+        self.drain(consumer)
+    }
+
+    /// Stop the consumer and wait for the outstanding messages to be
+    /// acknowledged, then reject or requeue the remaining ones and deregister
+    /// the consumer.
+    ///
+    /// A `Consumer::next()` call waiting for a message when the consumer is
+    /// stopped puts back the one it fetched, if any, and the remaining
+    /// messages are only swept once it returned. It returns within the block
+    /// timeout of the consumer.
+    ///
+    /// Returns the number of messages that were rejected or requeued.
+    pub fn drain<C: Codec>(&self, consumer: &Consumer<C>) -> Result<u64, Error> {
+        consumer.stop();
+
+        let start = Instant::now();
+        loop {
+            let n: u64 =
+                lock(consumer.client()).llen(consumer.processing_queue())?;
+            if n == 0 {
+                break;
+            }
+            let elapsed = start.elapsed();
+            if elapsed >= self.grace_period {
+                break;
+            }
+            thread::sleep(POLL_INTERVAL.min(self.grace_period - elapsed));
+        }
+
+        // A fetch still in flight puts back the message it gets, so that none
+        // lands in the processing queue after the sweep.
+        while consumer.is_fetching() {
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        let total = match self.action {
+            ShutdownAction::Reject => {
+                sweep(&self.sweep_script, consumer, consumer.unacked_queue(), 0)?
+            }
+            ShutdownAction::Requeue => {
+                sweep(&self.sweep_script, consumer, consumer.source_queue(), 1)?
+            }
+        };
+        consumer.deregister()?;
+        Ok(total)
+    }
+}
+
+/// Move all the messages of the *processing* queue of the consumer to the
+/// destination, or if `requeue` is 1 back to the source queue they were
+/// fetched from, and forget their deadlines.
+///
+/// The script is `SWEEP_SCRIPT`. Returns the number of moved messages.
+pub(crate) fn sweep<C: Codec>(
+    script: &Script,
+    consumer: &Consumer<C>,
+    destination: &str,
+    requeue: u8,
+) -> Result<u64, Error> {
+    let mut total: u64 = 0;
+    loop {
+        let n: u64 = script
+            .key(consumer.processing_queue())
+            .key(destination)
+            .key(consumer.origins_key())
+            .arg(SWEEP_BATCH_SIZE)
+            .arg(requeue)
+            .invoke(&mut *lock(consumer.client()))?;
+        total += n;
+        if n < SWEEP_BATCH_SIZE {
+            break;
+        }
+    }
+
+    let _: () = lock(consumer.client())
+        .del(&[consumer.deadlines_key(), consumer.inflight_key()])?;
+    Ok(total)
+}
@@ -0,0 +1,159 @@
+use crate::envelope;
+use crate::error::Error;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fmt;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// What a consumer with a `Signer` does with the messages that are unsigned
+/// or whose signature is invalid.
+///
+/// In all cases `Consumer::next()` reports them with `Error::Signature`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SignaturePolicy {
+    /// Move the message to the *unacked* queue, with the reason in the
+    /// rejections hash, like `MessageGuard::reject_with_reason()`.
+    Reject,
+    /// Move the message to the dead-letter queue of its source queue.
+    #[default]
+    DeadLetter,
+    /// Delete the message.
+    Drop,
+}
+
+/// The keys used to sign and verify messages with HMAC-SHA256, by id.
+///
+/// Producers sign with the primary key and record its id in the payload.
+/// Consumers accept messages signed with any of the keys, so that keys can be
+/// rotated with `Signer::rotate()` while older messages are still in the
+/// queues.
+///
+/// Consumers re-sign the messages they retry with the primary key, since the
+/// number of attempts changes the payload.
+#[derive(Clone)]
+pub struct Signer {
+    keys: HashMap<String, Vec<u8>>,
+    primary: String,
+}
+
+impl Signer {
+    /// Create a signer with the given primary key.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the id of the key is longer than 255 bytes.
+    pub fn new(id: &str, key: &[u8]) -> Signer {
+        let mut signer = Signer {
+            keys: HashMap::new(),
+            primary: id.into(),
+        };
+        signer.add_key(id, key);
+        signer
+    }
+
+    /// Add a key used only to verify messages.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the id of the key is longer than 255 bytes.
+    pub fn add_key(&mut self, id: &str, key: &[u8]) {
+        assert!(id.len() <= u8::MAX as usize, "key id is too long");
+        self.keys.insert(id.into(), key.to_vec());
+    }
+
+    /// Add a key and make it the primary key. The previous primary key is
+    /// kept to verify older messages.
+    pub fn rotate(&mut self, id: &str, key: &[u8]) {
+        self.add_key(id, key);
+        self.primary = id.into();
+    }
+
+    /// Remove a key that is not the primary key.
+    ///
+    /// Returns `false` if there is no such key or if it is the primary key.
+    pub fn remove_key(&mut self, id: &str) -> bool {
+        id != self.primary && self.keys.remove(id).is_some()
+    }
+
+    /// Get the id of the primary key.
+    pub fn primary_key_id(&self) -> &str {
+        &self.primary
+    }
+
+    /// Get the ids of all the keys.
+    pub fn key_ids(&self) -> Vec<&str> {
+        self.keys.keys().map(String::as_str).collect()
+    }
+
+    /// Sign a payload with the primary key.
+    pub(crate) fn sign(&self, payload: &[u8]) -> Vec<u8> {
+        let signature = mac(&self.keys[&self.primary], payload).finalize();
+        envelope::join_signature(&self.primary, &signature.into_bytes(), payload)
+    }
+
+    /// Verify the signature of a payload.
+    ///
+    /// Returns the signed payload.
+    pub(crate) fn verify<'a>(&self, payload: &'a [u8]) -> Result<&'a [u8], Error> {
+        let (id, signature, signed) = envelope::split_signature(payload)?
+            .ok_or_else(|| Error::Signature("message is not signed".into()))?;
+        let key = self.keys.get(id).ok_or_else(|| {
+            Error::Signature(format!("unknown key {}", id).into())
+        })?;
+        mac(key, signed).verify_slice(signature).map_err(|_| {
+            Error::Signature(format!("invalid signature with key {}", id).into())
+        })?;
+        Ok(signed)
+    }
+}
+
+fn mac(key: &[u8], payload: &[u8]) -> HmacSha256 {
+    // HMAC accepts keys of any size.
+    let mut mac = HmacSha256::new_from_slice(key).unwrap();
+    mac.update(payload);
+    mac
+}
+
+impl fmt::Debug for Signer {
+    // Keys are never printed.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Signer")
+            .field("keys", &self.key_ids())
+            .field("primary", &self.primary)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_and_verifies() {
+        let signer = Signer::new("k1", b"secret");
+        let signed = signer.sign(b"payload");
+        assert_eq!(b"payload", signer.verify(&signed).unwrap());
+
+        let other = Signer::new("k1", b"other");
+        assert!(other.verify(&signed).unwrap_err().is_signature());
+        let mut tampered = signed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(signer.verify(&tampered).unwrap_err().is_signature());
+        assert!(signer.verify(b"payload").unwrap_err().is_signature());
+    }
+
+    #[test]
+    fn rotated_keys_still_verify() {
+        let mut signer = Signer::new("k1", b"old");
+        let old = signer.sign(b"payload");
+        signer.rotate("k2", b"new");
+        let new = signer.sign(b"payload");
+
+        assert!(signer.verify(&old).is_ok());
+        assert!(signer.verify(&new).is_ok());
+        assert!(signer.remove_key("k1"));
+        assert!(signer.verify(&old).unwrap_err().is_signature());
+    }
+}
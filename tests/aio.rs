@@ -0,0 +1,233 @@
+#![cfg(feature = "aio")]
+
+use futures::StreamExt;
+use orizuru::{AsyncConsumer, AsyncProducer};
+use redis::Commands;
+use serde::{Deserialize, Serialize};
+use std::thread;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+#[derive(Deserialize, Serialize)]
+struct Message {
+    id: u64,
+}
+
+async fn fixture(
+    client: &redis::Client,
+) -> (redis::Connection, AsyncConsumer, AsyncProducer) {
+    let u = Uuid::new_v4();
+    let con = client.get_connection().unwrap();
+    let consumer = AsyncConsumer::with_fetch_connection(
+        format!("consumer-{}", u),
+        format!("q-{}", u),
+        client.get_async_connection().await.unwrap(),
+        client.get_async_connection().await.unwrap(),
+    );
+    let producer = AsyncProducer::new(
+        consumer.source_queue().into(),
+        client.get_async_connection().await.unwrap(),
+    );
+    (con, consumer, producer)
+}
+
+fn cleanup(con: &mut redis::Connection, consumer: &AsyncConsumer) {
+    let _: () = con.del(consumer.source_queue()).unwrap();
+    let _: () = con.del(consumer.processing_queue()).unwrap();
+    let _: () = con.del(consumer.unacked_queue()).unwrap();
+}
+
+#[tokio::test]
+async fn producer_can_enqueue() {
+    let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+    let (mut con, consumer, producer) = fixture(&client).await;
+
+    assert_eq!(0, producer.size().await);
+    producer.push(Message { id: 53 }).await.unwrap();
+    assert_eq!(1, producer.size().await);
+    assert_eq!(1, consumer.size().await);
+
+    let mut j = consumer.next::<Message>().await.unwrap().unwrap();
+    assert_eq!(53, j.id);
+    j.ack().await.unwrap();
+    assert_eq!(0, con.llen::<_, u64>(consumer.processing_queue()).unwrap());
+
+    cleanup(&mut con, &consumer);
+}
+
+#[tokio::test]
+async fn rejected_to_unack_queue() {
+    let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+    let (mut con, consumer, producer) = fixture(&client).await;
+
+    producer.push(Message { id: 42 }).await.unwrap();
+    let mut j = consumer.next::<Message>().await.unwrap().unwrap();
+    assert_eq!(1, con.llen::<_, u64>(consumer.processing_queue()).unwrap());
+    j.reject().await.unwrap();
+
+    assert_eq!(0, con.llen::<_, u64>(consumer.processing_queue()).unwrap());
+    assert_eq!(1, con.llen::<_, u64>(consumer.unacked_queue()).unwrap());
+
+    cleanup(&mut con, &consumer);
+}
+
+#[tokio::test]
+async fn dropped_are_rejected_on_next() {
+    let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+    let (mut con, consumer, producer) = fixture(&client).await;
+
+    producer.push(Message { id: 1 }).await.unwrap();
+    producer.push(Message { id: 2 }).await.unwrap();
+    {
+        let j = consumer.next::<Message>().await.unwrap().unwrap();
+        assert_eq!(1, j.id);
+    }
+    assert_eq!(1, con.llen::<_, u64>(consumer.processing_queue()).unwrap());
+
+    let mut j = consumer.next::<Message>().await.unwrap().unwrap();
+    assert_eq!(2, j.id);
+    j.ack().await.unwrap();
+
+    assert_eq!(0, con.llen::<_, u64>(consumer.processing_queue()).unwrap());
+    assert_eq!(1, con.llen::<_, u64>(consumer.unacked_queue()).unwrap());
+
+    cleanup(&mut con, &consumer);
+}
+
+#[tokio::test]
+async fn dropped_are_rejected_when_stopped() {
+    let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+    let (mut con, consumer, producer) = fixture(&client).await;
+
+    producer.push(Message { id: 1 }).await.unwrap();
+    let j = consumer.next::<Message>().await.unwrap().unwrap();
+    consumer.stop();
+    drop(j);
+
+    assert!(consumer.next::<Message>().await.is_none());
+    assert_eq!(0, con.llen::<_, u64>(consumer.processing_queue()).unwrap());
+    assert_eq!(1, con.llen::<_, u64>(consumer.unacked_queue()).unwrap());
+
+    cleanup(&mut con, &consumer);
+}
+
+#[tokio::test]
+async fn can_be_stopped() {
+    let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+    let (mut con, consumer, producer) = fixture(&client).await;
+
+    for i in 0..3 {
+        producer.push(Message { id: i }).await.unwrap();
+    }
+
+    while let Some(m) = consumer.next::<Message>().await {
+        m.unwrap().ack().await.unwrap();
+        consumer.stop();
+    }
+
+    assert_eq!(2, consumer.size().await);
+
+    cleanup(&mut con, &consumer);
+}
+
+#[tokio::test]
+async fn stream_ends_when_stopped() {
+    let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+    let (mut con, consumer, producer) = fixture(&client).await;
+
+    for i in 0..3 {
+        producer.push(Message { id: i }).await.unwrap();
+    }
+
+    let ids: Vec<u64> = consumer
+        .stream::<Message>()
+        .then(|m| async {
+            let mut m = m.unwrap();
+            m.ack().await.unwrap();
+            if m.id == 1 {
+                consumer.stop();
+            }
+            m.id
+        })
+        .collect()
+        .await;
+
+    assert_eq!(vec![0, 1], ids);
+    assert_eq!(1, consumer.size().await);
+
+    cleanup(&mut con, &consumer);
+}
+
+#[tokio::test]
+async fn stops_while_waiting_on_an_empty_queue() {
+    let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+    let (mut con, consumer, _) = fixture(&client).await;
+
+    let handle = consumer.stop_handle();
+    let stopper = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        handle.stop();
+    });
+
+    let now = Instant::now();
+    let ids: Vec<u64> = consumer
+        .stream::<Message>()
+        .map(|m| m.unwrap().id)
+        .collect()
+        .await;
+    assert!(ids.is_empty());
+    assert!(now.elapsed() < Duration::from_secs(3));
+    assert!(consumer.next::<Message>().await.is_none());
+    stopper.join().unwrap();
+
+    cleanup(&mut con, &consumer);
+}
+
+#[tokio::test]
+#[should_panic(expected = "streams require a fetch connection")]
+async fn streams_require_a_fetch_connection() {
+    let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+    let consumer = AsyncConsumer::new(
+        "consumer".into(),
+        "q".into(),
+        client.get_async_connection().await.unwrap(),
+    );
+    let _ = consumer.stream::<Message>();
+}
+
+#[tokio::test]
+async fn stream_acks_while_fetching() {
+    let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+    let mut con = client.get_connection().unwrap();
+    let u = Uuid::new_v4();
+    let consumer = AsyncConsumer::with_fetch_connection(
+        format!("consumer-{}", u),
+        format!("q-{}", u),
+        client.get_async_connection().await.unwrap(),
+        client.get_async_connection().await.unwrap(),
+    );
+    let producer = AsyncProducer::new(
+        consumer.source_queue().into(),
+        client.get_async_connection().await.unwrap(),
+    );
+
+    for i in 0..4 {
+        producer.push(Message { id: i }).await.unwrap();
+    }
+
+    let acked = consumer
+        .stream::<Message>()
+        .take(4)
+        .map(|m| async {
+            let mut m = m.unwrap();
+            m.ack().await.unwrap();
+        })
+        .buffer_unordered(2)
+        .count()
+        .await;
+
+    assert_eq!(4, acked);
+    assert_eq!(0, con.llen::<_, u64>(consumer.processing_queue()).unwrap());
+
+    cleanup(&mut con, &consumer);
+}
@@ -43,8 +43,8 @@ fn mixed_one_consumer() {
         }
 
         assert!(consumer.is_stopped());
-        assert_eq!(gc.collect(), Ok(7));
-        assert_eq!(gc.collect_one(consumer.name()), Ok(0));
+        assert_eq!(gc.collect().unwrap(), 7);
+        assert_eq!(gc.collect_one(consumer.name()).unwrap(), 0);
 
         assert!(consumer.deregister().is_ok());
         let cons: Vec<String> = con.smembers(CONSUMERS_KEY).unwrap();
@@ -0,0 +1,62 @@
+#![cfg(feature = "json")]
+
+use orizuru::{Codec, Consumer, JsonCodec, MsgPackCodec, Producer};
+use redis::Commands;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[macro_use]
+mod test_utils;
+
+#[derive(Deserialize, Serialize)]
+struct Message {
+    id: u64,
+}
+
+#[test]
+fn decodes_mixed_queues() {
+    redis_fixture!(client, con, consumer, "p", producer, {
+        let json_producer = Producer::new(
+            consumer.source_queue().into(),
+            client.get_connection().unwrap(),
+        )
+        .with_codec(JsonCodec);
+        assert_eq!(JsonCodec.tag(), json_producer.options().codec().tag());
+
+        producer.push(Message { id: 1 }).unwrap();
+        json_producer.push(Message { id: 2 }).unwrap();
+
+        let mut m = consumer.next::<Message>().unwrap().unwrap();
+        assert_eq!(1, m.id);
+        assert_eq!(
+            Some(MsgPackCodec.tag()),
+            m.envelope().map(|e| e.codec_tag())
+        );
+        m.ack().unwrap();
+        let mut m = consumer.next::<Message>().unwrap().unwrap();
+        assert_eq!(2, m.id);
+        assert_eq!(
+            Some(JsonCodec.tag()),
+            m.envelope().map(|e| e.codec_tag())
+        );
+        m.ack().unwrap();
+    });
+}
+
+#[test]
+fn decodes_bare_payloads_with_consumer_codec() {
+    redis_fixture!(client, con, consumer, {
+        let consumer = Consumer::new(
+            consumer.name().into(),
+            consumer.source_queue().into(),
+            client.get_connection().unwrap(),
+        )
+        .with_codec(JsonCodec);
+        let _: () = con.lpush(consumer.source_queue(), r#"{"id":7}"#).unwrap();
+
+        let mut m = consumer.next::<Message>().unwrap().unwrap();
+        assert_eq!(7, m.id);
+        assert!(m.envelope().is_none());
+        m.ack().unwrap();
+    });
+}
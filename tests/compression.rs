@@ -0,0 +1,56 @@
+#![cfg(feature = "zstd")]
+
+use orizuru::{Compression, Consumer, Producer};
+use redis::Commands;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[macro_use]
+mod test_utils;
+
+#[derive(Deserialize, Serialize)]
+struct Message {
+    id: u64,
+    data: String,
+}
+
+#[test]
+fn compresses_large_messages() {
+    redis_fixture!(client, con, consumer, {
+        let mut producer = Producer::new(
+            consumer.source_queue().into(),
+            client.get_connection().unwrap(),
+        );
+        producer.options_mut().set_compression(Some(Compression::Zstd));
+        producer.options_mut().set_compression_threshold(1024);
+
+        let data = "a".repeat(200 * 1024);
+        producer
+            .push(Message {
+                id: 1,
+                data: data.clone(),
+            })
+            .unwrap();
+        producer
+            .push(Message {
+                id: 2,
+                data: "small".into(),
+            })
+            .unwrap();
+
+        let payloads: Vec<Vec<u8>> =
+            con.lrange(consumer.source_queue(), 0, -1).unwrap();
+        assert!(payloads.iter().all(|p| p.len() < 1024));
+
+        let mut m = consumer.next::<Message>().unwrap().unwrap();
+        assert_eq!((1, &data), (m.id, &m.data));
+        let envelope = m.envelope().unwrap();
+        assert_eq!(Some(Compression::Zstd), envelope.compression());
+        m.ack().unwrap();
+
+        let mut m = consumer.next::<Message>().unwrap().unwrap();
+        assert_eq!(2, m.id);
+        assert_eq!(None, m.envelope().unwrap().compression());
+        m.ack().unwrap();
+    });
+}
@@ -1,4 +1,6 @@
-use orizuru::{Consumer, CONSUMERS_KEY};
+use orizuru::{
+    Consumer, DeadLetterQueue, RetryOutcome, RetryPolicy, CONSUMERS_KEY,
+};
 use redis::{Commands, Value};
 use rmp_serde::Serializer;
 use serde::{Deserialize, Serialize};
@@ -51,8 +53,8 @@ fn unacked_to_unack_queue() {
             assert_eq!(sample_job_payload(42), in_proc[0]);
         }
 
-        assert_eq!(0, con.llen(pqueue).unwrap());
-        assert_eq!(1, con.llen(uqueue).unwrap());
+        assert_eq!(0, con.llen::<_, u64>(pqueue).unwrap());
+        assert_eq!(1, con.llen::<_, u64>(uqueue).unwrap());
     });
 }
 
@@ -77,7 +79,7 @@ fn rejected_to_unack_queue() {
 
         let in_proc_size: u32 = con.llen(pqueue).unwrap();
         assert_eq!(0, in_proc_size);
-        assert_eq!(1, con.llen(uqueue).unwrap());
+        assert_eq!(1, con.llen::<_, u64>(uqueue).unwrap());
     });
 }
 
@@ -114,13 +116,211 @@ fn can_be_stopped() {
 
         while let Some(m) = consumer.next::<Message>() {
             let _m = m.unwrap();
-            let _ = consumer.stop();
+            consumer.stop();
         }
 
         assert_eq!(2, consumer.size());
     });
 }
 
+#[test]
+fn can_be_stopped_from_another_thread() {
+    let u = Uuid::new_v4();
+    let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+    let mut consumer = Consumer::new(
+        format!("consumer-{}", u),
+        format!("q-{}", u),
+        client.get_connection().unwrap(),
+    );
+    consumer.set_block_timeout(time::Duration::from_millis(100));
+    assert_eq!(time::Duration::from_millis(100), consumer.block_timeout());
+
+    let handle = consumer.stop_handle();
+    let stopper = thread::spawn(move || {
+        thread::sleep(time::Duration::from_millis(200));
+        handle.stop();
+    });
+
+    let now = time::Instant::now();
+    assert!(consumer.next::<Message>().is_none());
+    assert!(consumer.is_stopped());
+    assert!(now.elapsed() < time::Duration::from_secs(5));
+    stopper.join().unwrap();
+}
+
+#[test]
+fn acks_while_waiting_for_the_next_message() {
+    redis_fixture!(client, con, consumer, {
+        consumer.set_fetch_connection(client.get_connection().unwrap());
+        consumer.set_block_timeout(time::Duration::from_secs(1));
+        let _: () = con
+            .lpush(consumer.source_queue(), sample_job_payload(42))
+            .unwrap();
+
+        let mut m = consumer.next::<Message>().unwrap().unwrap();
+        thread::scope(|s| {
+            let waiting = s.spawn(|| consumer.next::<Message>().is_none());
+            thread::sleep(time::Duration::from_millis(100));
+            let now = time::Instant::now();
+            m.ack().unwrap();
+            assert!(now.elapsed() < time::Duration::from_millis(500));
+            consumer.stop();
+            assert!(waiting.join().unwrap());
+        });
+        assert_eq!(0, con.llen::<_, u64>(consumer.processing_queue()).unwrap());
+    });
+}
+
+#[test]
+fn is_send_and_sync() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Consumer>();
+}
+
+#[test]
+fn run_catches_panics() {
+    redis_fixture!(client, con, consumer, {
+        for i in 0..3 {
+            let _: () = con
+                .lpush(consumer.source_queue(), sample_job_payload(i))
+                .unwrap();
+        }
+
+        let res = consumer.run(|m: &Message| {
+            if m.id == 2 {
+                consumer.stop();
+            }
+            match m.id {
+                0 => panic!("boom"),
+                1 => Err(format!("failed job {}", m.id)),
+                _ => Ok(()),
+            }
+        });
+
+        assert!(res.is_ok());
+        assert_eq!(0, consumer.size());
+        assert_eq!(0, con.llen::<_, u64>(consumer.processing_queue()).unwrap());
+        assert_eq!(2, con.llen::<_, u64>(consumer.unacked_queue()).unwrap());
+        assert_eq!(
+            Some("handler panicked: boom".to_string()),
+            consumer.rejection_reason(&sample_job_payload(0)).unwrap()
+        );
+        assert_eq!(
+            Some("failed job 1".to_string()),
+            consumer.rejection_reason(&sample_job_payload(1)).unwrap()
+        );
+        assert_eq!(
+            None,
+            consumer.rejection_reason(&sample_job_payload(2)).unwrap()
+        );
+
+        let _: () = con.del(consumer.rejections_key()).unwrap();
+    });
+}
+
+#[test]
+fn deregister_forgets_rejection_reasons() {
+    redis_fixture!(client, con, consumer, {
+        let _: () = con
+            .lpush(consumer.source_queue(), sample_job_payload(1))
+            .unwrap();
+        let _: Value = consumer.register().unwrap();
+        let mut m = consumer.next::<Message>().unwrap().unwrap();
+        m.reject_with_reason("failed").unwrap();
+        drop(m);
+
+        let _: Value = consumer.deregister().unwrap();
+        assert_eq!(
+            None,
+            consumer.rejection_reason(&sample_job_payload(1)).unwrap()
+        );
+    });
+}
+
+#[test]
+fn run_rejects_undecodable() {
+    redis_fixture!(client, con, consumer, {
+        let _: () = con.lpush(consumer.source_queue(), "garbage").unwrap();
+        let _: () = con
+            .lpush(consumer.source_queue(), sample_job_payload(1))
+            .unwrap();
+
+        let res = consumer.run(|_: &Message| {
+            consumer.stop();
+            Ok::<(), String>(())
+        });
+
+        assert!(res.is_ok());
+        assert_eq!(0, con.llen::<_, u64>(consumer.processing_queue()).unwrap());
+        let unacked: Vec<Vec<u8>> =
+            con.lrange(consumer.unacked_queue(), 0, -1).unwrap();
+        assert_eq!(vec![b"garbage".to_vec()], unacked);
+        assert!(consumer.rejection_reason(b"garbage").unwrap().is_some());
+
+        let _: () = con.del(consumer.rejections_key()).unwrap();
+    });
+}
+
+#[test]
+fn retried_are_delayed() {
+    redis_fixture!(client, con, consumer, {
+        consumer
+            .set_retry_policy(RetryPolicy::new(3, time::Duration::from_secs(1)));
+
+        let _: () = con
+            .lpush(consumer.source_queue(), sample_job_payload(42))
+            .unwrap();
+
+        {
+            let mut m = consumer.next::<Message>().unwrap().unwrap();
+            assert_eq!(0, m.attempts());
+            let outcome = m.retry().unwrap();
+            assert_eq!(
+                RetryOutcome::Scheduled(time::Duration::from_secs(1)),
+                outcome
+            );
+        }
+
+        assert_eq!(0, con.llen::<_, u64>(consumer.processing_queue()).unwrap());
+        assert_eq!(0, con.llen::<_, u64>(consumer.unacked_queue()).unwrap());
+        assert_eq!(1, con.zcard::<_, u64>(consumer.delayed_queue()).unwrap());
+
+        let now = time::Instant::now();
+        let m = consumer.next::<Message>().unwrap().unwrap();
+        assert!(now.elapsed() >= time::Duration::from_millis(500));
+        assert_eq!(42, m.id);
+        assert_eq!(1, m.attempts());
+        assert_eq!(0, con.zcard::<_, u64>(consumer.delayed_queue()).unwrap());
+    });
+}
+
+#[test]
+fn exhausted_are_dead_lettered() {
+    redis_fixture!(client, con, consumer, {
+        consumer
+            .set_retry_policy(RetryPolicy::new(1, time::Duration::from_secs(1)));
+
+        let _: () = con
+            .lpush(consumer.source_queue(), sample_job_payload(42))
+            .unwrap();
+
+        let mut m = consumer.next::<Message>().unwrap().unwrap();
+        assert_eq!(RetryOutcome::DeadLettered, m.retry().unwrap());
+
+        assert_eq!(0, con.llen::<_, u64>(consumer.processing_queue()).unwrap());
+        assert_eq!(0, con.zcard::<_, u64>(consumer.delayed_queue()).unwrap());
+        assert_eq!(1, con.llen::<_, u64>(consumer.dead_letter_queue()).unwrap());
+
+        let dlq = DeadLetterQueue::new(
+            consumer.source_queue().into(),
+            client.get_connection().unwrap(),
+        );
+        let entries = dlq.list(0, 1).unwrap();
+        assert_eq!("exhausted 1 attempts", entries[0].reason());
+        assert_eq!(consumer.name(), entries[0].consumer());
+    });
+}
+
 #[test]
 fn no_heartbeat() {
     redis_fixture!(client, con, consumer, {
@@ -183,6 +383,32 @@ fn multiple_heartbeat() {
     });
 }
 
+#[test]
+fn background_heartbeat() {
+    // ugly hack to ensure this test runs after the previous ones, because
+    // they check the whole heartbeats hash
+    thread::sleep(time::Duration::from_millis(600));
+    redis_fixture!(client, con, consumer, {
+        consumer.start_heartbeat(
+            client.get_connection().unwrap(),
+            time::Duration::from_millis(50),
+            time::Duration::from_millis(200),
+        );
+        thread::sleep(time::Duration::from_millis(300));
+        let single: Value = con.get(consumer.heartbeat_key()).unwrap();
+        assert_ne!(single, Value::Nil);
+
+        consumer.stop();
+        thread::sleep(time::Duration::from_millis(400));
+        let single: Value = con.get(consumer.heartbeat_key()).unwrap();
+        assert_eq!(single, Value::Nil);
+
+        let _: () = con
+            .hdel(consumer.heartbeats_key(), consumer.name())
+            .unwrap();
+    });
+}
+
 #[test]
 fn register_deregister() {
     redis_fixture!(client, con, consumer, {
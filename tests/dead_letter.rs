@@ -0,0 +1,152 @@
+use orizuru::{Consumer, DeadLetterQueue, Producer};
+use redis::Commands;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[macro_use]
+mod test_utils;
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct Message {
+    id: u64,
+}
+
+#[test]
+fn dead_lettered_keep_reason() {
+    redis_fixture!(client, con, consumer, "p", producer, {
+        producer.push(Message { id: 42 }).unwrap();
+
+        let mut m = consumer.next::<Message>().unwrap().unwrap();
+        m.dead_letter("invalid id").unwrap();
+        let id = m.id().unwrap().to_string();
+        drop(m);
+
+        assert_eq!(0, con.llen::<_, u64>(consumer.processing_queue()).unwrap());
+        assert_eq!(0, con.llen::<_, u64>(consumer.unacked_queue()).unwrap());
+
+        let dlq = DeadLetterQueue::new(
+            consumer.source_queue().into(),
+            client.get_connection().unwrap(),
+        );
+        assert_eq!(1, dlq.size().unwrap());
+        let entries = dlq.list(0, 10).unwrap();
+        assert_eq!(1, entries.len());
+        assert_eq!(id, entries[0].id());
+        assert_eq!("invalid id", entries[0].reason());
+        assert_eq!(consumer.name(), entries[0].consumer());
+        assert_eq!(Message { id: 42 }, entries[0].message().unwrap());
+        assert_eq!(Some(entries[0].clone()), dlq.get(&id).unwrap());
+        assert_eq!(None, dlq.get("missing").unwrap());
+    });
+}
+
+#[test]
+fn dead_lettered_can_be_replayed() {
+    redis_fixture!(client, con, consumer, "p", producer, {
+        producer.push(Message { id: 1 }).unwrap();
+        producer.push(Message { id: 2 }).unwrap();
+
+        let mut m = consumer.next::<Message>().unwrap().unwrap();
+        m.dead_letter("first").unwrap();
+        let id = m.id().unwrap().to_string();
+        drop(m);
+        let mut m = consumer.next::<Message>().unwrap().unwrap();
+        m.dead_letter("second").unwrap();
+        drop(m);
+
+        let dlq = DeadLetterQueue::new(
+            consumer.source_queue().into(),
+            client.get_connection().unwrap(),
+        );
+        assert!(dlq.replay(&id).unwrap());
+        assert!(!dlq.replay(&id).unwrap());
+        assert_eq!(1, dlq.size().unwrap());
+        assert_eq!(1, con.llen::<_, u64>(consumer.source_queue()).unwrap());
+
+        let m = consumer.next::<Message>().unwrap().unwrap();
+        assert_eq!(1, m.id);
+        assert_eq!(Some(id.as_str()), m.id());
+        assert_eq!(0, m.attempts());
+        drop(m);
+
+        assert_eq!(1, dlq.replay_all().unwrap());
+        assert_eq!(0, dlq.size().unwrap());
+    });
+}
+
+#[test]
+fn dead_lettered_can_be_purged() {
+    redis_fixture!(client, con, consumer, "p", producer, {
+        for id in 0..3 {
+            producer.push(Message { id }).unwrap();
+        }
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            let mut m = consumer.next::<Message>().unwrap().unwrap();
+            m.dead_letter("failed").unwrap();
+            ids.push(m.id().unwrap().to_string());
+        }
+
+        let dlq = DeadLetterQueue::new(
+            consumer.source_queue().into(),
+            client.get_connection().unwrap(),
+        );
+        assert!(dlq.purge(&ids[0]).unwrap());
+        assert!(!dlq.purge(&ids[0]).unwrap());
+        assert_eq!(2, dlq.purge_all().unwrap());
+        assert_eq!(0, dlq.size().unwrap());
+        assert_eq!(0, con.llen::<_, u64>(consumer.source_queue()).unwrap());
+    });
+}
+
+#[test]
+fn corrupt_entries_are_skipped() {
+    redis_fixture!(client, con, consumer, "p", producer, {
+        producer.push(Message { id: 1 }).unwrap();
+        let mut m = consumer.next::<Message>().unwrap().unwrap();
+        m.dead_letter("failed").unwrap();
+        let id = m.id().unwrap().to_string();
+        drop(m);
+        let _: () = con.lpush(consumer.dead_letter_queue(), "corrupt").unwrap();
+
+        let dlq = DeadLetterQueue::new(
+            consumer.source_queue().into(),
+            client.get_connection().unwrap(),
+        );
+        assert_eq!("failed", dlq.get(&id).unwrap().unwrap().reason());
+        assert!(dlq.get("unknown").unwrap().is_none());
+        let entries = dlq.list(0, usize::MAX).unwrap();
+        assert_eq!(1, entries.len());
+        assert_eq!(id, entries[0].id());
+        assert!(dlq.replay(&id).unwrap());
+        assert_eq!(1, dlq.size().unwrap());
+    });
+}
+
+#[test]
+fn replay_all_pages_through_the_queue() {
+    redis_fixture!(client, con, consumer, "p", producer, {
+        for id in 0..150 {
+            producer.push(Message { id }).unwrap();
+            let mut m = consumer.next::<Message>().unwrap().unwrap();
+            m.dead_letter("failed").unwrap();
+            if id == 75 {
+                let _: () =
+                    con.lpush(consumer.dead_letter_queue(), "corrupt").unwrap();
+            }
+        }
+
+        let dlq = DeadLetterQueue::new(
+            consumer.source_queue().into(),
+            client.get_connection().unwrap(),
+        );
+        assert_eq!(150, dlq.replay_all().unwrap());
+        assert_eq!(1, dlq.size().unwrap());
+        assert_eq!(150, con.llen::<_, u64>(consumer.source_queue()).unwrap());
+        for id in 0..150 {
+            let mut m = consumer.next::<Message>().unwrap().unwrap();
+            assert_eq!(id, m.id);
+            m.ack().unwrap();
+        }
+    });
+}
@@ -0,0 +1,125 @@
+#![cfg(feature = "aes-gcm")]
+
+use orizuru::{Cipher, Consumer, DeadLetterQueue, Keyring, Producer};
+use redis::Commands;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[macro_use]
+mod test_utils;
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Message {
+    ssn: String,
+}
+
+#[test]
+fn encrypts_and_decrypts() {
+    redis_fixture!(client, con, consumer, {
+        let mut keyring = Keyring::new("k1", Cipher::Aes256Gcm, [1; 32]);
+        let mut producer = Producer::new(
+            consumer.source_queue().into(),
+            client.get_connection().unwrap(),
+        );
+        producer.options_mut().set_keyring(keyring.clone());
+        producer
+            .push(Message {
+                ssn: "secret".into(),
+            })
+            .unwrap();
+
+        keyring.rotate("k2", Cipher::Aes256Gcm, [2; 32]);
+        producer.options_mut().set_keyring(keyring.clone());
+        producer
+            .push(Message {
+                ssn: "secret".into(),
+            })
+            .unwrap();
+
+        let payloads: Vec<Vec<u8>> =
+            con.lrange(consumer.source_queue(), 0, -1).unwrap();
+        assert!(payloads
+            .iter()
+            .all(|p| !p.windows(6).any(|w| w == b"secret")));
+
+        consumer.set_keyring(keyring);
+        for key_id in &["k1", "k2"] {
+            let mut m = consumer.next::<Message>().unwrap().unwrap();
+            assert_eq!("secret", m.ssn);
+            assert_eq!(Some(*key_id), m.envelope().unwrap().key_id());
+            m.ack().unwrap();
+        }
+    });
+}
+
+#[test]
+fn undecryptable_are_dead_lettered() {
+    redis_fixture!(client, con, consumer, {
+        let keyring = Keyring::new("k1", Cipher::Aes256Gcm, [1; 32]);
+        let mut producer = Producer::new(
+            consumer.source_queue().into(),
+            client.get_connection().unwrap(),
+        );
+        producer.options_mut().set_keyring(keyring.clone());
+        producer
+            .push(Message {
+                ssn: "secret".into(),
+            })
+            .unwrap();
+
+        consumer.set_keyring(Keyring::new("k2", Cipher::Aes256Gcm, [2; 32]));
+        let err = consumer.next::<Message>().unwrap().err().unwrap();
+        assert!(err.is_decrypt());
+        assert_eq!(0, con.llen::<_, u64>(consumer.processing_queue()).unwrap());
+
+        let dlq = DeadLetterQueue::new(
+            consumer.source_queue().into(),
+            client.get_connection().unwrap(),
+        );
+        let entries = dlq.list(0, 1).unwrap();
+        assert_eq!(1, entries.len());
+        assert!(entries[0].message::<Message>().unwrap_err().is_decrypt());
+        let m: Message = entries[0].decrypted_message(&keyring).unwrap();
+        assert_eq!("secret", m.ssn);
+    });
+}
+
+#[cfg(feature = "aio")]
+#[tokio::test]
+async fn undecryptable_are_dead_lettered_by_async_consumers() {
+    use orizuru::{AsyncConsumer, AsyncProducer};
+
+    let u = Uuid::new_v4();
+    let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+    let mut con = client.get_connection().unwrap();
+    let mut consumer = AsyncConsumer::new(
+        format!("consumer-{}", u),
+        format!("q-{}", u),
+        client.get_async_connection().await.unwrap(),
+    );
+    let mut producer = AsyncProducer::new(
+        consumer.source_queue().into(),
+        client.get_async_connection().await.unwrap(),
+    );
+    producer
+        .options_mut()
+        .set_keyring(Keyring::new("k1", Cipher::Aes256Gcm, [1; 32]));
+    producer
+        .push(Message {
+            ssn: "secret".into(),
+        })
+        .await
+        .unwrap();
+
+    consumer.set_keyring(Keyring::new("k2", Cipher::Aes256Gcm, [2; 32]));
+    let err = consumer.next::<Message>().await.unwrap().err().unwrap();
+    assert!(err.is_decrypt());
+    assert_eq!(0, con.llen::<_, u64>(consumer.processing_queue()).unwrap());
+
+    let dlq = DeadLetterQueue::new(
+        consumer.source_queue().into(),
+        client.get_connection().unwrap(),
+    );
+    assert_eq!(1, dlq.size().unwrap());
+    dlq.purge_all().unwrap();
+}
@@ -1,4 +1,4 @@
-use orizuru::{Consumer, GC};
+use orizuru::{CollectMode, Consumer, GcRunner, Producer, GC};
 use redis::{Commands, Value};
 use rmp_serde::Serializer;
 use serde::{Deserialize, Serialize};
@@ -21,10 +21,28 @@ fn sample_job_payload(id: u64) -> Vec<u8> {
     buf
 }
 
+fn two_source_consumer(client: &redis::Client, u: Uuid) -> Consumer {
+    Consumer::with_source_queues(
+        format!("consumer-{}", u),
+        vec![format!("q1-{}", u), format!("q2-{}", u)],
+        client.get_connection().unwrap(),
+    )
+}
+
+fn cleanup(con: &mut redis::Connection, consumer: &Consumer) {
+    for source in consumer.source_queues() {
+        let _: () = con.del(source.as_str()).unwrap();
+    }
+    let _: () = con.del(consumer.processing_queue()).unwrap();
+    let _: () = con.del(consumer.unacked_queue()).unwrap();
+    let _: () = con.del(consumer.origins_key()).unwrap();
+    let _: Value = consumer.deregister().unwrap();
+}
+
 #[test]
 fn collect_one_runs_with_no_jobs() {
     redis_fixture!(client, con, consumer, "g", gc, {
-        assert_eq!(gc.collect_one(consumer.name()), Ok(0));
+        assert_eq!(gc.collect_one(consumer.name()).unwrap(), 0);
     });
 }
 
@@ -36,14 +54,14 @@ fn collect_one_runs_with_some_jobs() {
                 .lpush(consumer.unacked_queue(), sample_job_payload(i))
                 .unwrap();
         }
-        assert_eq!(gc.collect_one(consumer.name()), Ok(3));
+        assert_eq!(gc.collect_one(consumer.name()).unwrap(), 3);
     });
 }
 
 #[test]
 fn collect_noop_with_no_consumers() {
     redis_fixture!(client, con, consumer, "g", gc, {
-        assert_eq!(gc.collect(), Ok(0));
+        assert_eq!(gc.collect().unwrap(), 0);
     });
 }
 
@@ -51,7 +69,7 @@ fn collect_noop_with_no_consumers() {
 fn collect_runs_with_a_consumer_and_no_jobs() {
     redis_fixture!(client, con, consumer, "g", gc, {
         let _: Value = consumer.register().unwrap();
-        assert_eq!(gc.collect(), Ok(0));
+        assert_eq!(gc.collect().unwrap(), 0);
     });
 }
 
@@ -72,6 +90,264 @@ fn collect_runs_with_a_consumer_and_some_jobs() {
                 .lpush(consumer.unacked_queue(), sample_job_payload(i))
                 .unwrap();
         }
-        assert_eq!(gc.collect(), Ok(6));
+        assert_eq!(gc.collect().unwrap(), 6);
+    });
+}
+
+#[test]
+fn collect_dead_requeues_expired_consumers() {
+    redis_fixture!(client, con, consumer, "g", gc, {
+        let _: Value = consumer.register().unwrap();
+        for i in 0..2 {
+            let _: () = con
+                .lpush(consumer.processing_queue(), sample_job_payload(i))
+                .unwrap();
+        }
+        let _: () = con
+            .lpush(consumer.unacked_queue(), sample_job_payload(2))
+            .unwrap();
+
+        consumer.heartbeat(time::Duration::from_secs(5));
+        assert_eq!(gc.collect_dead_one(consumer.name()).unwrap(), None);
+
+        consumer.heartbeat(time::Duration::from_millis(50));
+        thread::sleep(time::Duration::from_millis(100));
+        assert_eq!(gc.collect_dead_one(consumer.name()).unwrap(), Some(3));
+
+        assert_eq!(3, consumer.size());
+        assert_eq!(0, con.llen::<_, u64>(consumer.processing_queue()).unwrap());
+        let registered: bool = con
+            .sismember(consumer.consumers_key(), consumer.name())
+            .unwrap();
+        assert!(!registered);
+        assert_eq!(gc.collect_dead_one(consumer.name()).unwrap(), None);
+    });
+}
+
+#[test]
+fn collect_dead_ignores_consumers_without_heartbeat() {
+    redis_fixture!(client, con, consumer, "g", gc, {
+        let _: Value = consumer.register().unwrap();
+        let _: () = con
+            .lpush(consumer.processing_queue(), sample_job_payload(1))
+            .unwrap();
+
+        assert_eq!(gc.collect_dead_one(consumer.name()).unwrap(), None);
+        assert_eq!(1, con.llen::<_, u64>(consumer.processing_queue()).unwrap());
+
+        let _: Value = consumer.deregister().unwrap();
+    });
+}
+
+#[test]
+fn collect_one_can_requeue_to_source() {
+    redis_fixture!(client, con, consumer, {
+        let mut gc = GC::new(client.get_connection().unwrap());
+        gc.set_mode(CollectMode::Source);
+        let _: Value = consumer.register().unwrap();
+        for i in 0..3 {
+            let _: () = con
+                .lpush(consumer.unacked_queue(), sample_job_payload(i))
+                .unwrap();
+        }
+
+        assert_eq!(gc.collect_one(consumer.name()).unwrap(), 3);
+        assert_eq!(3, consumer.size());
+        assert_eq!(0, con.llen::<_, u64>(consumer.processing_queue()).unwrap());
+
+        let _: Value = consumer.deregister().unwrap();
+    });
+}
+
+#[test]
+fn collect_one_runs_in_batches() {
+    redis_fixture!(client, con, consumer, {
+        let mut gc = GC::new(client.get_connection().unwrap());
+        gc.set_batch_size(2);
+        for i in 0..5 {
+            let _: () = con
+                .lpush(consumer.unacked_queue(), sample_job_payload(i))
+                .unwrap();
+        }
+
+        assert_eq!(gc.collect_one(consumer.name()).unwrap(), 5);
+        assert_eq!(0, con.llen::<_, u64>(consumer.unacked_queue()).unwrap());
+        assert_eq!(5, con.llen::<_, u64>(consumer.processing_queue()).unwrap());
+    });
+}
+
+#[test]
+fn runner_records_stats() {
+    redis_fixture!(client, con, consumer, {
+        let _: Value = consumer.register().unwrap();
+        for i in 0..3 {
+            let _: () = con
+                .lpush(consumer.unacked_queue(), sample_job_payload(i))
+                .unwrap();
+        }
+
+        let gc = GC::new(client.get_connection().unwrap());
+        let runner = GcRunner::start(gc, time::Duration::from_millis(50));
+        thread::sleep(time::Duration::from_millis(200));
+
+        let stats = runner.stats();
+        assert!(stats.runs() >= 2);
+        assert!(stats.last_run_at().is_some());
+        assert_eq!(None, stats.last_error());
+        assert_eq!(Some(&3), stats.recovered().get(consumer.name()));
+        assert!(stats.last_recovered_at().contains_key(consumer.name()));
+        assert!(runner.stop().is_some());
+
+        let _: Value = consumer.deregister().unwrap();
+    });
+}
+
+#[test]
+fn collect_expired_requeues_timed_out_messages() {
+    redis_fixture!(client, con, consumer, "p", producer, {
+        consumer.set_visibility_timeout(Some(time::Duration::from_millis(100)));
+        let _: Value = consumer.register().unwrap();
+        let gc = GC::new(client.get_connection().unwrap());
+        producer.push(Message { id: 1 }).unwrap();
+        producer.push(Message { id: 2 }).unwrap();
+
+        let m1 = consumer.next::<Message>().unwrap().unwrap();
+        let mut m2 = consumer.next::<Message>().unwrap().unwrap();
+        assert_eq!(2, con.zcard::<_, u64>(consumer.deadlines_key()).unwrap());
+        assert!(m1.extend(time::Duration::from_secs(5)).unwrap());
+
+        thread::sleep(time::Duration::from_millis(200));
+        assert_eq!(gc.collect_expired_one(consumer.name()).unwrap(), 1);
+        assert_eq!(1, consumer.size());
+        assert_eq!(1, con.llen::<_, u64>(consumer.processing_queue()).unwrap());
+        assert!(!m2.extend(time::Duration::from_secs(5)).unwrap());
+
+        m2.ack().unwrap();
+        drop(m1);
+        assert_eq!(0, con.zcard::<_, u64>(consumer.deadlines_key()).unwrap());
+
+        let _: Value = consumer.deregister().unwrap();
+    });
+}
+
+#[test]
+fn collect_one_requeues_to_the_origin() {
+    let u = Uuid::new_v4();
+    let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+    let mut con = client.get_connection().unwrap();
+    let consumer = two_source_consumer(&client, u);
+    let _: Value = consumer.register().unwrap();
+    let sources = consumer.source_queues().to_vec();
+    let producer =
+        Producer::new(sources[1].clone(), client.get_connection().unwrap());
+    producer.push(Message { id: 1 }).unwrap();
+
+    let mut m = consumer.next::<Message>().unwrap().unwrap();
+    assert_eq!(sources[1], m.source_queue());
+    m.reject().unwrap();
+    drop(m);
+
+    let mut gc = GC::new(client.get_connection().unwrap());
+    gc.set_mode(CollectMode::Source);
+    assert_eq!(gc.collect_one(consumer.name()).unwrap(), 1);
+    assert_eq!(0, con.llen::<_, u64>(sources[0].as_str()).unwrap());
+    assert_eq!(1, con.llen::<_, u64>(sources[1].as_str()).unwrap());
+    assert_eq!(0, con.hlen::<_, u64>(consumer.origins_key()).unwrap());
+
+    cleanup(&mut con, &consumer);
+}
+
+#[test]
+fn collect_dead_requeues_to_the_origin_in_batches() {
+    let u = Uuid::new_v4();
+    let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+    let mut con = client.get_connection().unwrap();
+    let consumer = two_source_consumer(&client, u);
+    let _: Value = consumer.register().unwrap();
+    let sources = consumer.source_queues().to_vec();
+    for (i, source) in sources.iter().enumerate() {
+        let producer =
+            Producer::new(source.clone(), client.get_connection().unwrap());
+        for j in 0..2 {
+            producer.push(Message { id: (i * 2 + j) as u64 }).unwrap();
+        }
+    }
+    let mut messages = Vec::new();
+    for _ in 0..4 {
+        messages.push(consumer.next::<Message>().unwrap().unwrap());
+    }
+    // Leave two messages in the processing queue, and reject the others.
+    let mut messages = messages.into_iter();
+    messages.by_ref().take(2).for_each(std::mem::forget);
+    drop(messages);
+
+    consumer.heartbeat(time::Duration::from_millis(50));
+    thread::sleep(time::Duration::from_millis(100));
+    let mut gc = GC::new(client.get_connection().unwrap());
+    gc.set_batch_size(3);
+    assert_eq!(gc.collect_dead_one(consumer.name()).unwrap(), Some(4));
+    assert_eq!(2, con.llen::<_, u64>(sources[0].as_str()).unwrap());
+    assert_eq!(2, con.llen::<_, u64>(sources[1].as_str()).unwrap());
+    assert_eq!(0, con.hlen::<_, u64>(consumer.origins_key()).unwrap());
+    let registered: bool = con
+        .sismember(consumer.consumers_key(), consumer.name())
+        .unwrap();
+    assert!(!registered);
+
+    cleanup(&mut con, &consumer);
+}
+
+#[test]
+fn collect_expired_requeues_to_the_origin() {
+    let u = Uuid::new_v4();
+    let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+    let mut con = client.get_connection().unwrap();
+    let mut consumer = two_source_consumer(&client, u);
+    consumer.set_visibility_timeout(Some(time::Duration::from_millis(100)));
+    let _: Value = consumer.register().unwrap();
+    let sources = consumer.source_queues().to_vec();
+    let producer =
+        Producer::new(sources[1].clone(), client.get_connection().unwrap());
+    producer.push(Message { id: 1 }).unwrap();
+
+    let m = consumer.next::<Message>().unwrap().unwrap();
+    let id = m.id().unwrap().to_string();
+    let payload = m.payload().to_vec();
+    assert!(con
+        .zscore::<_, _, Option<u64>>(consumer.deadlines_key(), id.as_str())
+        .unwrap()
+        .is_some());
+    let inflight: Vec<u8> =
+        con.hget(consumer.inflight_key(), id.as_str()).unwrap();
+    assert_eq!(payload, inflight);
+
+    thread::sleep(time::Duration::from_millis(200));
+    let gc = GC::new(client.get_connection().unwrap());
+    assert_eq!(gc.collect_expired_one(consumer.name()).unwrap(), 1);
+    assert_eq!(0, con.llen::<_, u64>(sources[0].as_str()).unwrap());
+    assert_eq!(1, con.llen::<_, u64>(sources[1].as_str()).unwrap());
+    assert_eq!(0, con.zcard::<_, u64>(consumer.deadlines_key()).unwrap());
+    assert_eq!(0, con.hlen::<_, u64>(consumer.inflight_key()).unwrap());
+    assert_eq!(0, con.hlen::<_, u64>(consumer.origins_key()).unwrap());
+    assert!(!m.extend(time::Duration::from_secs(5)).unwrap());
+    std::mem::forget(m);
+
+    cleanup(&mut con, &consumer);
+}
+
+#[test]
+fn collect_one_forgets_rejection_reasons() {
+    redis_fixture!(client, con, consumer, "p", producer, {
+        let gc = GC::new(client.get_connection().unwrap());
+        producer.push(Message { id: 1 }).unwrap();
+        let mut m = consumer.next::<Message>().unwrap().unwrap();
+        let payload = m.payload().to_vec();
+        m.reject_with_reason("failed").unwrap();
+        drop(m);
+        assert!(consumer.rejection_reason(&payload).unwrap().is_some());
+
+        assert_eq!(gc.collect_one(consumer.name()).unwrap(), 1);
+        assert_eq!(None, consumer.rejection_reason(&payload).unwrap());
+        assert_eq!(0, con.hlen::<_, u64>(consumer.rejections_key()).unwrap());
     });
 }
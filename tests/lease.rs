@@ -0,0 +1,81 @@
+use orizuru::{Consumer, Lease, GC};
+use redis::{Commands, Value};
+use std::thread;
+use std::time::Duration;
+use uuid::Uuid;
+
+#[macro_use]
+mod test_utils;
+
+fn cleanup(con: &mut redis::Connection, lease: &Lease) {
+    let _: () = con.del(lease.key()).unwrap();
+    let _: () = con.del(lease.token_key()).unwrap();
+}
+
+#[test]
+fn only_one_holder_at_a_time() {
+    let name = format!("lease-{}", Uuid::new_v4());
+    let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+    let mut con = client.get_connection().unwrap();
+    let ttl = Duration::from_secs(5);
+    let a = Lease::new(&name, ttl, client.get_connection().unwrap());
+    let b = Lease::new(&name, ttl, client.get_connection().unwrap());
+
+    let token = a.acquire().unwrap();
+    assert!(token.is_some());
+    assert_eq!(None, b.acquire().unwrap());
+    assert_eq!(token, a.acquire().unwrap());
+
+    assert!(!b.release().unwrap());
+    assert!(a.release().unwrap());
+    assert_eq!(token.map(|t| t + 1), b.acquire().unwrap());
+    assert_eq!(None, a.acquire().unwrap());
+
+    cleanup(&mut con, &a);
+}
+
+#[test]
+fn expired_lease_is_taken_over() {
+    let name = format!("lease-{}", Uuid::new_v4());
+    let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+    let mut con = client.get_connection().unwrap();
+    let ttl = Duration::from_millis(100);
+    let a = Lease::new(&name, ttl, client.get_connection().unwrap());
+    let b = Lease::new(&name, ttl, client.get_connection().unwrap());
+
+    assert!(a.acquire().unwrap().is_some());
+    assert_eq!(None, b.acquire().unwrap());
+    thread::sleep(Duration::from_millis(200));
+    assert!(b.acquire().unwrap().is_some());
+    assert_eq!(None, a.acquire().unwrap());
+
+    cleanup(&mut con, &a);
+}
+
+#[test]
+fn only_the_leader_collects() {
+    redis_fixture!(client, con, consumer, {
+        let name = format!("lease-{}", Uuid::new_v4());
+        let ttl = Duration::from_secs(5);
+        let mut leader = GC::new(client.get_connection().unwrap());
+        leader.set_lease(Lease::new(&name, ttl, client.get_connection().unwrap()));
+        let mut follower = GC::new(client.get_connection().unwrap());
+        follower.set_lease(Lease::new(
+            &name,
+            ttl,
+            client.get_connection().unwrap(),
+        ));
+
+        let _: Value = consumer.register().unwrap();
+        let _: () = con.lpush(consumer.unacked_queue(), "payload").unwrap();
+        let _: () = con.lpush(consumer.unacked_queue(), "payload").unwrap();
+
+        assert_eq!(leader.collect_one(consumer.name()).unwrap(), 2);
+        let _: () = con.lpush(consumer.unacked_queue(), "payload").unwrap();
+        assert_eq!(follower.collect_one(consumer.name()).unwrap(), 0);
+        assert_eq!(leader.collect_one(consumer.name()).unwrap(), 1);
+
+        let _: Value = consumer.deregister().unwrap();
+        cleanup(&mut con, leader.lease().unwrap());
+    });
+}
@@ -0,0 +1,93 @@
+use orizuru::{Producer, WorkerPool};
+use redis::Commands;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time;
+use uuid::Uuid;
+
+#[derive(Deserialize, Serialize)]
+struct Message {
+    id: u64,
+}
+
+#[test]
+fn pool_acks_and_rejects() {
+    let u = Uuid::new_v4();
+    let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+    let mut con = client.get_connection().unwrap();
+    let queue = format!("q-{}", u);
+
+    let producer = Producer::new(queue.clone(), client.get_connection().unwrap());
+    for i in 0..10 {
+        producer.push(Message { id: i }).unwrap();
+    }
+
+    let mut pool = WorkerPool::new(client.clone(), queue.clone(), 3);
+    pool.set_block_timeout(time::Duration::from_millis(100));
+    assert_eq!(3, pool.concurrency());
+    assert_ne!(pool.consumer_name(0), pool.consumer_name(1));
+
+    let processed = Arc::new(AtomicUsize::new(0));
+    let count = processed.clone();
+    let handle = pool.stop_handle();
+    pool.run(move |m: &Message| {
+        if count.fetch_add(1, Ordering::SeqCst) == 9 {
+            handle.stop();
+        }
+        if m.id < 5 {
+            Ok(())
+        } else {
+            Err("failed")
+        }
+    });
+
+    assert_eq!(10, processed.load(Ordering::SeqCst));
+    assert_eq!(0, producer.size());
+
+    let mut unacked = 0;
+    for i in 0..pool.concurrency() {
+        let name = pool.consumer_name(i);
+        let processing = format!("orizuru:consumers:{}:processing", name);
+        let unack = format!("orizuru:consumers:{}:unacked", name);
+        assert_eq!(0, con.llen::<_, u64>(processing.as_str()).unwrap());
+        let n: u64 = con.llen(unack.as_str()).unwrap();
+        unacked += n;
+        let _: () = con.del(unack.as_str()).unwrap();
+    }
+    assert_eq!(5, unacked);
+
+    let _: () = con.del(queue.as_str()).unwrap();
+}
+
+#[test]
+fn pool_requeues_messages_left_by_a_failed_consumer() {
+    let u = Uuid::new_v4();
+    let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+    let mut con = client.get_connection().unwrap();
+    let queue = format!("q-{}", u);
+
+    let mut pool = WorkerPool::new(client.clone(), queue.clone(), 1);
+    pool.set_block_timeout(time::Duration::from_millis(100));
+
+    // A consumer whose connection dropped while processing a message leaves it
+    // in its processing queue, which the restarted one shares.
+    let processing =
+        format!("orizuru:consumers:{}:processing", pool.consumer_name(0));
+    let stranded =
+        Producer::new(processing.clone(), client.get_connection().unwrap());
+    stranded.push(Message { id: 7 }).unwrap();
+
+    let processed = Arc::new(AtomicUsize::new(0));
+    let id = processed.clone();
+    let handle = pool.stop_handle();
+    pool.run(move |m: &Message| {
+        id.store(m.id as usize, Ordering::SeqCst);
+        handle.stop();
+        Ok::<(), String>(())
+    });
+
+    assert_eq!(7, processed.load(Ordering::SeqCst));
+    assert_eq!(0, con.llen::<_, u64>(processing.as_str()).unwrap());
+    assert_eq!(0, con.llen::<_, u64>(queue.as_str()).unwrap());
+}
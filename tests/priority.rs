@@ -0,0 +1,67 @@
+use orizuru::{Consumer, Priority, PriorityMode, PriorityProducer};
+use redis::Commands;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[macro_use]
+mod test_utils;
+
+#[derive(Deserialize, Serialize)]
+struct Message {
+    id: u64,
+}
+
+#[test]
+fn strict_mode_fetches_by_priority() {
+    redis_fixture!(client, con, consumer, {
+        consumer.set_priority_mode(PriorityMode::Strict);
+        let producer = PriorityProducer::new(
+            consumer.source_queue().into(),
+            client.get_connection().unwrap(),
+        );
+        producer.push(Message { id: 3 }, Priority::Low).unwrap();
+        producer.push(Message { id: 2 }, Priority::Normal).unwrap();
+        producer.push(Message { id: 1 }, Priority::High).unwrap();
+        assert_eq!(1, producer.size(Priority::High));
+        assert_eq!(1, consumer.size());
+
+        for id in 1..=3 {
+            let mut m = consumer.next::<Message>().unwrap().unwrap();
+            assert_eq!(id, m.id);
+            assert_eq!(
+                1,
+                con.llen::<_, u64>(consumer.processing_queue()).unwrap()
+            );
+            m.ack().unwrap();
+        }
+
+        for priority in &Priority::ALL {
+            let _: () = con
+                .del(priority.queue_name(consumer.source_queue()))
+                .unwrap();
+        }
+    });
+}
+
+#[test]
+fn rejected_keep_reliability() {
+    redis_fixture!(client, con, consumer, {
+        consumer.set_priority_mode(PriorityMode::Weighted {
+            high: 1,
+            normal: 1,
+            low: 1,
+        });
+        let producer = PriorityProducer::new(
+            consumer.source_queue().into(),
+            client.get_connection().unwrap(),
+        );
+        producer.push(Message { id: 1 }, Priority::Low).unwrap();
+
+        let mut m = consumer.next::<Message>().unwrap().unwrap();
+        assert_eq!(1, m.id);
+        m.reject().unwrap();
+        assert_eq!(0, con.llen::<_, u64>(consumer.processing_queue()).unwrap());
+        assert_eq!(1, con.llen::<_, u64>(consumer.unacked_queue()).unwrap());
+        assert_eq!(0, producer.size(Priority::Low));
+    });
+}
@@ -1,6 +1,7 @@
 use orizuru::{Consumer, Producer};
 use redis::Commands;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use uuid::Uuid;
 
 #[macro_use]
@@ -26,3 +27,36 @@ fn producer_can_enqueue() {
         assert_eq!(53, j.id);
     });
 }
+
+#[test]
+fn producer_wraps_in_envelope() {
+    redis_fixture!(client, con, consumer, "p", producer, {
+        let mut headers = HashMap::new();
+        headers.insert("origin".to_string(), "tests".to_string());
+        producer
+            .push_with_headers(Message { id: 53 }, headers)
+            .unwrap();
+
+        let j = consumer.next::<Message>().unwrap().unwrap();
+        assert_eq!(53, j.id);
+        assert!(j.id().is_some());
+        assert!(j.created_at().is_some());
+        assert_eq!(0, j.attempts());
+        assert_eq!(Some("tests"), j.header("origin"));
+        assert_eq!(None, j.header("missing"));
+    });
+}
+
+#[test]
+fn bare_payloads_have_no_envelope() {
+    redis_fixture!(client, con, consumer, {
+        let payload = rmp_serde::encode::to_vec(&Message { id: 7 }).unwrap();
+        let _: () = con.lpush(consumer.source_queue(), payload).unwrap();
+
+        let j = consumer.next::<Message>().unwrap().unwrap();
+        assert_eq!(7, j.id);
+        assert!(j.envelope().is_none());
+        assert_eq!(None, j.id());
+        assert_eq!(0, j.attempts());
+    });
+}
@@ -0,0 +1,42 @@
+#![cfg(feature = "protobuf")]
+
+use orizuru::{Codec, Consumer, Producer, Protobuf, ProtobufCodec};
+use redis::Commands;
+use uuid::Uuid;
+
+#[macro_use]
+mod test_utils;
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct Job {
+    #[prost(uint64, tag = "1")]
+    id: u64,
+}
+
+#[test]
+fn pushes_and_consumes_protobuf_messages() {
+    redis_fixture!(client, con, consumer, {
+        let producer = Producer::new(
+            consumer.source_queue().into(),
+            client.get_connection().unwrap(),
+        )
+        .with_codec(ProtobufCodec);
+        producer.push(Protobuf(Job { id: 42 })).unwrap();
+
+        let mut m = consumer.next::<Protobuf<Job>>().unwrap().unwrap();
+        assert_eq!(42, m.id);
+        assert_eq!(
+            Some(ProtobufCodec.tag()),
+            m.envelope().map(|e| e.codec_tag())
+        );
+        m.ack().unwrap();
+    });
+}
+
+#[test]
+fn default_codec_rejects_protobuf_messages() {
+    redis_fixture!(client, con, consumer, "p", producer, {
+        assert!(producer.push(Protobuf(Job { id: 42 })).is_err());
+        assert_eq!(0, consumer.size());
+    });
+}
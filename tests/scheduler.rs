@@ -0,0 +1,64 @@
+use orizuru::{Consumer, Producer, Scheduler, DELAYED_QUEUES_KEY};
+use redis::Commands;
+use serde::{Deserialize, Serialize};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+#[macro_use]
+mod test_utils;
+
+#[derive(Deserialize, Serialize)]
+struct Message {
+    id: u64,
+}
+
+#[test]
+fn push_in_delays_delivery() {
+    redis_fixture!(client, con, consumer, "p", producer, {
+        producer
+            .push_in(Message { id: 1 }, Duration::from_millis(500))
+            .unwrap();
+        assert_eq!(0, producer.size());
+        assert_eq!(1, con.zcard::<_, u64>(consumer.delayed_queue()).unwrap());
+        let registered: bool = con
+            .sismember(DELAYED_QUEUES_KEY, consumer.source_queue())
+            .unwrap();
+        assert!(registered);
+
+        let scheduler = Scheduler::new(client.get_connection().unwrap());
+        assert_eq!(0, scheduler.schedule_one(consumer.source_queue()).unwrap());
+
+        thread::sleep(Duration::from_millis(600));
+        assert_eq!(1, scheduler.schedule_one(consumer.source_queue()).unwrap());
+        assert_eq!(1, producer.size());
+        assert_eq!(0, con.zcard::<_, u64>(consumer.delayed_queue()).unwrap());
+
+        let m = consumer.next::<Message>().unwrap().unwrap();
+        assert_eq!(1, m.id);
+
+        let _: () = con
+            .srem(DELAYED_QUEUES_KEY, consumer.source_queue())
+            .unwrap();
+    });
+}
+
+#[test]
+fn push_at_in_the_past_is_due() {
+    redis_fixture!(client, con, consumer, "p", producer, {
+        let when = SystemTime::now() - Duration::from_secs(60);
+        producer.push_at(Message { id: 1 }, when).unwrap();
+        producer
+            .push_at(Message { id: 2 }, when + Duration::from_secs(3600))
+            .unwrap();
+
+        let scheduler = Scheduler::new(client.get_connection().unwrap());
+        assert!(scheduler.schedule().unwrap() >= 1);
+        assert_eq!(1, producer.size());
+        assert_eq!(1, con.zcard::<_, u64>(consumer.delayed_queue()).unwrap());
+
+        let _: () = con
+            .srem(DELAYED_QUEUES_KEY, consumer.source_queue())
+            .unwrap();
+    });
+}
@@ -0,0 +1,130 @@
+use orizuru::{Consumer, Producer, Shutdown, ShutdownAction, CONSUMERS_KEY};
+use redis::Commands;
+use rmp_serde::Serializer;
+use serde::{Deserialize, Serialize};
+use std::thread;
+use std::time;
+use uuid::Uuid;
+
+#[macro_use]
+mod test_utils;
+
+#[derive(Deserialize, Serialize)]
+struct Message {
+    id: u64,
+}
+
+fn sample_job_payload(id: u64) -> Vec<u8> {
+    let mut buf = Vec::new();
+    let job = Message { id };
+    job.serialize(&mut Serializer::new(&mut buf)).unwrap();
+    buf
+}
+
+#[test]
+fn drain_requeues_outstanding() {
+    redis_fixture!(client, con, consumer, {
+        assert!(consumer.register().is_ok());
+        for i in 0..3 {
+            let _: () = con
+                .lpush(consumer.processing_queue(), sample_job_payload(i))
+                .unwrap();
+        }
+
+        let shutdown = Shutdown::new(time::Duration::from_millis(200));
+        assert_eq!(shutdown.drain(&consumer).unwrap(), 3);
+
+        assert!(consumer.is_stopped());
+        assert_eq!(3, consumer.size());
+        assert_eq!(0, con.llen::<_, u64>(consumer.processing_queue()).unwrap());
+        let cons: Vec<String> = con.smembers(CONSUMERS_KEY).unwrap();
+        assert!(!cons.contains(&String::from(consumer.name())));
+    });
+}
+
+#[test]
+fn drain_rejects_outstanding() {
+    redis_fixture!(client, con, consumer, {
+        for i in 0..3 {
+            let _: () = con
+                .lpush(consumer.processing_queue(), sample_job_payload(i))
+                .unwrap();
+        }
+
+        let mut shutdown = Shutdown::new(time::Duration::from_millis(200));
+        shutdown.set_action(ShutdownAction::Reject);
+        assert_eq!(shutdown.drain(&consumer).unwrap(), 3);
+
+        assert_eq!(0, consumer.size());
+        assert_eq!(3, con.llen::<_, u64>(consumer.unacked_queue()).unwrap());
+    });
+}
+
+#[test]
+fn drain_waits_for_acks() {
+    redis_fixture!(client, con, consumer, {
+        let _: () = con
+            .lpush(consumer.source_queue(), sample_job_payload(42))
+            .unwrap();
+
+        let shutdown = Shutdown::new(time::Duration::from_secs(5));
+        let mut m = consumer.next::<Message>().unwrap().unwrap();
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(time::Duration::from_millis(300));
+                m.ack().unwrap();
+            });
+            assert_eq!(shutdown.drain(&consumer).unwrap(), 0);
+        });
+
+        assert_eq!(0, consumer.size());
+        assert_eq!(0, con.llen::<_, u64>(consumer.unacked_queue()).unwrap());
+    });
+}
+
+#[test]
+fn drain_requeues_to_the_origin() {
+    let u = Uuid::new_v4();
+    let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+    let mut con = client.get_connection().unwrap();
+    let sources = vec![format!("q1-{}", u), format!("q2-{}", u)];
+    let consumer = Consumer::with_source_queues(
+        format!("consumer-{}", u),
+        sources.clone(),
+        client.get_connection().unwrap(),
+    );
+    let producer =
+        Producer::new(sources[1].clone(), client.get_connection().unwrap());
+    producer.push(Message { id: 1 }).unwrap();
+    let m = consumer.next::<Message>().unwrap().unwrap();
+    std::mem::forget(m);
+
+    let shutdown = Shutdown::new(time::Duration::from_millis(0));
+    assert_eq!(shutdown.drain(&consumer).unwrap(), 1);
+
+    assert_eq!(0, con.llen::<_, u64>(sources[0].as_str()).unwrap());
+    assert_eq!(1, con.llen::<_, u64>(sources[1].as_str()).unwrap());
+    assert_eq!(0, con.hlen::<_, u64>(consumer.origins_key()).unwrap());
+    let _: () = con.del(sources[1].as_str()).unwrap();
+}
+
+#[test]
+fn drain_puts_back_messages_fetched_after_the_stop() {
+    redis_fixture!(client, con, consumer, {
+        consumer.set_fetch_connection(client.get_connection().unwrap());
+        let shutdown = Shutdown::new(time::Duration::from_secs(5));
+        thread::scope(|s| {
+            let fetch = s.spawn(|| consumer.next::<Message>().is_none());
+            thread::sleep(time::Duration::from_millis(100));
+            consumer.stop();
+            let _: () = con
+                .lpush(consumer.source_queue(), sample_job_payload(42))
+                .unwrap();
+            assert_eq!(shutdown.drain(&consumer).unwrap(), 0);
+            assert!(fetch.join().unwrap());
+        });
+
+        assert_eq!(1, consumer.size());
+        assert_eq!(0, con.llen::<_, u64>(consumer.processing_queue()).unwrap());
+    });
+}
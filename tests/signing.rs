@@ -0,0 +1,137 @@
+use orizuru::{Consumer, DeadLetterQueue, Producer, SignaturePolicy, Signer};
+use redis::Commands;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[macro_use]
+mod test_utils;
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Message {
+    id: u64,
+}
+
+#[test]
+fn signs_and_verifies() {
+    redis_fixture!(client, con, consumer, {
+        let mut signer = Signer::new("k1", b"secret");
+        let mut producer = Producer::new(
+            consumer.source_queue().into(),
+            client.get_connection().unwrap(),
+        );
+        producer.options_mut().set_signer(signer.clone());
+        producer.push(Message { id: 1 }).unwrap();
+
+        signer.rotate("k2", b"other secret");
+        producer.options_mut().set_signer(signer.clone());
+        producer.push(Message { id: 2 }).unwrap();
+
+        consumer.set_signer(signer);
+        for id in 1..=2 {
+            let mut m = consumer.next::<Message>().unwrap().unwrap();
+            assert_eq!(id, m.id);
+            m.ack().unwrap();
+        }
+        assert_eq!(0, con.llen::<_, u64>(consumer.processing_queue()).unwrap());
+    });
+}
+
+#[test]
+fn unverified_are_dead_lettered() {
+    redis_fixture!(client, con, consumer, {
+        let signer = Signer::new("k1", b"secret");
+        let mut producer = Producer::new(
+            consumer.source_queue().into(),
+            client.get_connection().unwrap(),
+        );
+        producer.push(Message { id: 1 }).unwrap();
+        producer.options_mut().set_signer(Signer::new("k1", b"forged"));
+        producer.push(Message { id: 2 }).unwrap();
+
+        consumer.set_signer(signer.clone());
+        for _ in 0..2 {
+            let err = consumer.next::<Message>().unwrap().err().unwrap();
+            assert!(err.is_signature());
+        }
+        assert_eq!(0, con.llen::<_, u64>(consumer.processing_queue()).unwrap());
+
+        let mut dlq = DeadLetterQueue::new(
+            consumer.source_queue().into(),
+            client.get_connection().unwrap(),
+        );
+        assert_eq!(2, dlq.size().unwrap());
+        let unsigned = dlq.list(0, 2).unwrap().pop().unwrap();
+        assert_eq!(1, unsigned.message::<Message>().unwrap().id);
+
+        dlq.set_signer(signer);
+        assert!(dlq.replay(unsigned.id()).unwrap_err().is_signature());
+        assert!(dlq.replay_unverified(unsigned.id()).unwrap());
+        let mut m = consumer.next::<Message>().unwrap().unwrap();
+        assert_eq!(1, m.id);
+        m.ack().unwrap();
+    });
+}
+
+#[test]
+fn unverified_are_rejected_or_dropped() {
+    redis_fixture!(client, con, consumer, {
+        let producer = Producer::new(
+            consumer.source_queue().into(),
+            client.get_connection().unwrap(),
+        );
+        producer.push(Message { id: 1 }).unwrap();
+        producer.push(Message { id: 2 }).unwrap();
+
+        consumer.set_signer(Signer::new("k1", b"secret"));
+        consumer.set_signature_policy(SignaturePolicy::Reject);
+        let err = consumer.next::<Message>().unwrap().err().unwrap();
+        assert!(err.is_signature());
+        let unacked: Vec<Vec<u8>> =
+            con.lrange(consumer.unacked_queue(), 0, -1).unwrap();
+        assert_eq!(1, unacked.len());
+        assert!(consumer.rejection_reason(&unacked[0]).unwrap().is_some());
+
+        consumer.set_signature_policy(SignaturePolicy::Drop);
+        let err = consumer.next::<Message>().unwrap().err().unwrap();
+        assert!(err.is_signature());
+        assert_eq!(1, con.llen::<_, u64>(consumer.unacked_queue()).unwrap());
+        assert_eq!(0, con.llen::<_, u64>(consumer.processing_queue()).unwrap());
+        assert_eq!(0, con.llen::<_, u64>(consumer.source_queue()).unwrap());
+    });
+}
+
+#[test]
+fn forged_are_not_signed_again_by_replays() {
+    redis_fixture!(client, con, consumer, {
+        let signer = Signer::new("k1", b"secret");
+        let mut producer = Producer::new(
+            consumer.source_queue().into(),
+            client.get_connection().unwrap(),
+        );
+        producer.options_mut().set_signer(Signer::new("k1", b"forged"));
+        producer.push(Message { id: 1 }).unwrap();
+        producer.options_mut().set_signer(signer.clone());
+        producer.push(Message { id: 2 }).unwrap();
+
+        consumer.set_signer(signer.clone());
+        assert!(consumer.next::<Message>().unwrap().err().unwrap().is_signature());
+        let mut m = consumer.next::<Message>().unwrap().unwrap();
+        m.dead_letter("failed").unwrap();
+        drop(m);
+
+        let mut dlq = DeadLetterQueue::new(
+            consumer.source_queue().into(),
+            client.get_connection().unwrap(),
+        );
+        dlq.set_signer(signer);
+        assert_eq!(1, dlq.replay_all().unwrap());
+        assert_eq!(1, dlq.size().unwrap());
+        let forged = dlq.list(0, 1).unwrap().pop().unwrap();
+        assert_eq!(1, forged.message::<Message>().unwrap().id);
+
+        let mut m = consumer.next::<Message>().unwrap().unwrap();
+        assert_eq!(2, m.id);
+        m.ack().unwrap();
+        assert_eq!(0, con.llen::<_, u64>(consumer.source_queue()).unwrap());
+    });
+}
@@ -0,0 +1,115 @@
+use orizuru::{Consumer, Producer, RetryOutcome, SourceOrder};
+use redis::Commands;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use uuid::Uuid;
+
+#[derive(Deserialize, Serialize)]
+struct Message {
+    id: u64,
+}
+
+fn multi_source_consumer(
+    client: &redis::Client,
+    u: Uuid,
+    order: SourceOrder,
+) -> Consumer {
+    let mut consumer = Consumer::with_source_queues(
+        format!("consumer-{}", u),
+        vec![format!("q1-{}", u), format!("q2-{}", u)],
+        client.get_connection().unwrap(),
+    );
+    consumer.set_source_order(order);
+    consumer
+}
+
+fn cleanup(con: &mut redis::Connection, consumer: &Consumer) {
+    for source in consumer.source_queues() {
+        let _: () = con.del(source.as_str()).unwrap();
+        let _: () = con
+            .del(format!("orizuru:queues:{}:delayed", source))
+            .unwrap();
+    }
+    let _: () = con.del(consumer.processing_queue()).unwrap();
+    let _: () = con.del(consumer.unacked_queue()).unwrap();
+}
+
+#[test]
+fn strict_order_drains_first_queue() {
+    let u = Uuid::new_v4();
+    let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+    let mut con = client.get_connection().unwrap();
+    let consumer = multi_source_consumer(&client, u, SourceOrder::Strict);
+    let sources = consumer.source_queues().to_vec();
+    let p1 = Producer::new(sources[0].clone(), client.get_connection().unwrap());
+    let p2 = Producer::new(sources[1].clone(), client.get_connection().unwrap());
+
+    p2.push(Message { id: 3 }).unwrap();
+    p1.push(Message { id: 1 }).unwrap();
+    p1.push(Message { id: 2 }).unwrap();
+    assert_eq!(3, consumer.size());
+
+    for (id, source) in &[(1, &sources[0]), (2, &sources[0]), (3, &sources[1])] {
+        let mut m = consumer.next::<Message>().unwrap().unwrap();
+        assert_eq!(*id, m.id);
+        assert_eq!(source.as_str(), m.source_queue());
+        m.ack().unwrap();
+    }
+
+    cleanup(&mut con, &consumer);
+}
+
+#[test]
+fn round_robin_alternates_queues() {
+    let u = Uuid::new_v4();
+    let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+    let mut con = client.get_connection().unwrap();
+    let consumer = multi_source_consumer(&client, u, SourceOrder::RoundRobin);
+    let sources = consumer.source_queues().to_vec();
+    let p1 = Producer::new(sources[0].clone(), client.get_connection().unwrap());
+    let p2 = Producer::new(sources[1].clone(), client.get_connection().unwrap());
+
+    for id in 0..2 {
+        p1.push(Message { id }).unwrap();
+        p2.push(Message { id: id + 10 }).unwrap();
+    }
+
+    let fetched: Vec<String> = (0..4)
+        .map(|_| {
+            let mut m = consumer.next::<Message>().unwrap().unwrap();
+            m.ack().unwrap();
+            m.source_queue().to_string()
+        })
+        .collect();
+    assert_ne!(fetched[0], fetched[1]);
+    assert_ne!(fetched[2], fetched[3]);
+
+    cleanup(&mut con, &consumer);
+}
+
+#[test]
+fn retries_go_to_their_source() {
+    let u = Uuid::new_v4();
+    let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+    let mut con = client.get_connection().unwrap();
+    let consumer = multi_source_consumer(&client, u, SourceOrder::Strict);
+    let sources = consumer.source_queues().to_vec();
+    let p2 = Producer::new(sources[1].clone(), client.get_connection().unwrap());
+
+    p2.push(Message { id: 1 }).unwrap();
+    let mut m = consumer.next::<Message>().unwrap().unwrap();
+    match m.retry().unwrap() {
+        RetryOutcome::Scheduled(d) => assert!(d <= Duration::from_secs(1)),
+        RetryOutcome::DeadLettered => panic!("unexpected dead letter"),
+    }
+    drop(m);
+
+    let delayed = format!("orizuru:queues:{}:delayed", sources[1]);
+    assert_eq!(1, con.zcard::<_, u64>(delayed).unwrap());
+    assert_eq!(0, con.zcard::<_, u64>(consumer.delayed_queue()).unwrap());
+
+    cleanup(&mut con, &consumer);
+    let _: () = con
+        .srem(orizuru::DELAYED_QUEUES_KEY, sources[1].as_str())
+        .unwrap();
+}
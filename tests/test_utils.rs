@@ -5,7 +5,8 @@ macro_rules! redis_fixture {
         let $client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
         let mut $con = $client.get_connection().unwrap();
         let con2 = $client.get_connection().unwrap();
-        let $consumer = Consumer::new(
+        #[allow(unused_mut)]
+        let mut $consumer = Consumer::new(
             format!("consumer-{}", u).into(),
             format!("q-{}", u).into(),
             con2,
@@ -13,12 +14,16 @@ macro_rules! redis_fixture {
         let _: () = $con.del($consumer.source_queue()).unwrap();
         let _: () = $con.del($consumer.processing_queue()).unwrap();
         let _: () = $con.del($consumer.unacked_queue()).unwrap();
+        let _: () = $con.del($consumer.delayed_queue()).unwrap();
+        let _: () = $con.del($consumer.dead_letter_queue()).unwrap();
 
         $code
 
         let _: () = $con.del($consumer.source_queue()).unwrap();
         let _: () = $con.del($consumer.processing_queue()).unwrap();
         let _: () = $con.del($consumer.unacked_queue()).unwrap();
+        let _: () = $con.del($consumer.delayed_queue()).unwrap();
+        let _: () = $con.del($consumer.dead_letter_queue()).unwrap();
     };
 
     ($client:ident, $con:ident, $consumer:ident, "p", $producer:ident, $code:block) => {